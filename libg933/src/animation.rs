@@ -0,0 +1,327 @@
+//! Software-driven lighting animations
+//!
+//! The headset only knows a handful of effects by itself, so anything fancier is rendered here
+//! and streamed to the device as a series of temporary static color writes.
+
+use failure::Error;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lights::{Color, Config, Effect, Light, ProfileType};
+use Device;
+
+/// Time between frames that the device can comfortably keep up with
+pub const FRAME_TIME: Duration = Duration::from_millis(100);
+
+/// How a transition speeds up and slows down over time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    /// Constant speed
+    Linear,
+    /// Start slow and speed up
+    EaseIn,
+    /// Start fast and slow down
+    EaseOut,
+    /// Start slow, speed up and slow down again
+    EaseInOut,
+}
+
+impl Easing {
+    /// Map linear progress from 0 to 1 to eased progress
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t).powi(3),
+            Easing::EaseInOut => {
+                if t < 0.5 {
+                    4.0 * t * t * t
+                } else {
+                    1.0 - (2.0 - 2.0 * t).powi(3) / 2.0
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Easing {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "linear" => Ok(Easing::Linear),
+            "ease-in" => Ok(Easing::EaseIn),
+            "ease-out" => Ok(Easing::EaseOut),
+            "ease-in-out" => Ok(Easing::EaseInOut),
+            s => bail!("Invalid easing: {}", s),
+        }
+    }
+}
+
+/// The colors to show on each light for a single frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    /// Color of the logo light, or `None` to leave it alone
+    pub logo: Option<Color>,
+    /// Color of the side lights, or `None` to leave them alone
+    pub side: Option<Color>,
+}
+
+/// Something that can be played back on the headset lights with `run`
+pub trait Animation {
+    /// Get the frame to show at `time` after the start of the animation, or `None` once the
+    /// animation is over
+    fn frame(&mut self, time: Duration) -> Option<Frame>;
+}
+
+/// Rainbow sweeping across the logo and side lights
+#[derive(Debug)]
+pub struct Rainbow {
+    /// Time for one full trip around the color wheel
+    pub period: Duration,
+}
+
+impl Animation for Rainbow {
+    fn frame(&mut self, time: Duration) -> Option<Frame> {
+        let hue = fraction(time, self.period);
+        Some(Frame {
+            logo: Some(Color::from_hsv(hue * 360.0, 1.0, 1.0)),
+            // Trail the logo by a bit so the colors appear to move across the headset
+            side: Some(Color::from_hsv((hue - 0.15) * 360.0, 1.0, 1.0)),
+        })
+    }
+}
+
+/// Two colors swapping places between the logo and side lights
+#[derive(Debug)]
+pub struct Alternate {
+    /// The two colors to alternate between
+    pub colors: [Color; 2],
+    /// Time until the lights are back to where they started
+    pub period: Duration,
+}
+
+impl Animation for Alternate {
+    fn frame(&mut self, time: Duration) -> Option<Frame> {
+        let (first, second) = if fraction(time, self.period) < 0.5 {
+            (self.colors[0], self.colors[1])
+        } else {
+            (self.colors[1], self.colors[0])
+        };
+        Some(Frame {
+            logo: Some(first),
+            side: Some(second),
+        })
+    }
+}
+
+/// All lights flashing on and off
+#[derive(Debug)]
+pub struct Strobe {
+    /// Color of the flashes
+    pub color: Color,
+    /// Time between the start of each flash
+    pub period: Duration,
+}
+
+impl Animation for Strobe {
+    fn frame(&mut self, time: Duration) -> Option<Frame> {
+        let color = if fraction(time, self.period) < 0.5 {
+            self.color
+        } else {
+            Color::new(0, 0, 0)
+        };
+        Some(Frame {
+            logo: Some(color),
+            side: Some(color),
+        })
+    }
+}
+
+/// All lights fading back and forth between two colors
+#[derive(Debug)]
+pub struct Fade {
+    /// Color at the start of the cycle
+    pub from: Color,
+    /// Color halfway through the cycle
+    pub to: Color,
+    /// Time to fade to the second color and back again
+    pub period: Duration,
+}
+
+impl Animation for Fade {
+    fn frame(&mut self, time: Duration) -> Option<Frame> {
+        // A transition there and another one back
+        let half = self.period / 2;
+        let into_half = self.period.mul_f32(fraction(time, self.period));
+        let color = if into_half < half {
+            Transition::new(Light::Logo, self.from, self.to, half).color(into_half)
+        } else {
+            Transition::new(Light::Logo, self.to, self.from, half).color(into_half - half)
+        };
+        Some(Frame {
+            logo: Some(color),
+            side: Some(color),
+        })
+    }
+}
+
+/// One light fading from one color to another, ending once it gets there. This is what
+/// `Device::transition_lights` plays
+#[derive(Debug)]
+pub struct Transition {
+    /// Light to fade
+    pub light: Light,
+    /// Color at the start
+    pub from: Color,
+    /// Color at the end
+    pub to: Color,
+    /// Time to get from one to the other
+    pub duration: Duration,
+    /// How the fade speeds up and slows down
+    pub easing: Easing,
+}
+
+impl Transition {
+    /// Create a transition that eases in and out
+    pub fn new(light: Light, from: Color, to: Color, duration: Duration) -> Self {
+        Self {
+            light,
+            from,
+            to,
+            duration,
+            easing: Easing::EaseInOut,
+        }
+    }
+
+    /// Color shown at `time` after the start
+    pub fn color(&self, time: Duration) -> Color {
+        if time >= self.duration {
+            return self.to;
+        }
+        let t = time.as_secs_f32() / self.duration.as_secs_f32();
+        self.from.mix(self.to, self.easing.apply(t))
+    }
+}
+
+impl Animation for Transition {
+    fn frame(&mut self, time: Duration) -> Option<Frame> {
+        if time >= self.duration {
+            return None;
+        }
+        let color = Some(self.color(time));
+        Some(match self.light {
+            Light::Logo => Frame {
+                logo: color,
+                side: None,
+            },
+            Light::Side => Frame {
+                logo: None,
+                side: color,
+            },
+        })
+    }
+}
+
+/// Play an animation on a device, writing a new frame every `frame_time` until the animation
+/// ends or `running` is cleared. The device is given back control of its lights afterwards
+pub fn run<A: Animation + ?Sized>(
+    device: &mut Device,
+    animation: &mut A,
+    frame_time: Duration,
+    running: &AtomicBool,
+) -> Result<(), Error> {
+    device.set_lighting_host_control(true)?;
+    let result = play(device, animation, frame_time, running, true);
+    let released = device.set_lighting_host_control(false);
+    result.and(released)
+}
+
+/// Play a transition to the end, sending its colors as they are
+pub(crate) fn play_transition(
+    device: &mut Device,
+    transition: &mut Transition,
+) -> Result<(), Error> {
+    play(
+        device,
+        transition,
+        FRAME_TIME,
+        &AtomicBool::new(true),
+        false,
+    )
+}
+
+/// Write the frames of an animation, correcting their colors for the device if `correct` is set
+fn play<A: Animation + ?Sized>(
+    device: &mut Device,
+    animation: &mut A,
+    frame_time: Duration,
+    running: &AtomicBool,
+    correct: bool,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let mut deadline = start;
+    let mut last = Frame {
+        logo: None,
+        side: None,
+    };
+
+    while running.load(Ordering::SeqCst) {
+        let frame = match animation.frame(start.elapsed()) {
+            Some(frame) => frame,
+            None => break,
+        };
+
+        // Only write lights that the model has and that actually changed, every request costs us
+        // a round trip
+        let lights = [
+            (Light::Logo, frame.logo, last.logo),
+            (Light::Side, frame.side, last.side),
+        ];
+        for &(light, color, last_color) in &lights {
+            if !device.model().lights.contains(&light) || color == last_color {
+                continue;
+            }
+            if let Some(color) = color {
+                set_static(device, light, color, correct)?;
+            }
+        }
+        last = frame;
+
+        deadline += frame_time;
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        } else {
+            // We fell behind, so drop the missed frames instead of rushing to catch up
+            deadline = now;
+        }
+    }
+
+    Ok(())
+}
+
+fn set_static(device: &mut Device, light: Light, color: Color, correct: bool) -> Result<(), Error> {
+    let color = if correct {
+        device.correct_color(color)
+    } else {
+        color
+    };
+    device.set_lights(&Config {
+        light,
+        effect: Effect::Static { color },
+        profile_type: ProfileType::Temporary,
+    })?;
+    Ok(())
+}
+
+/// How far `time` is into the current cycle of length `period`, from 0 to 1
+fn fraction(time: Duration, period: Duration) -> f32 {
+    if period == Duration::from_secs(0) {
+        return 0.0;
+    }
+    (time.as_secs_f64() / period.as_secs_f64()).fract() as f32
+}
@@ -0,0 +1,362 @@
+//! Audio-reactive lighting
+//!
+//! Only signed 16 bit little endian PCM is supported, which is what `parec` and `pw-record`
+//! produce by default.
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use failure::Error;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::io::{self, Read};
+use std::time::Duration;
+
+use animation::{Animation, Frame};
+use lights::Color;
+
+/// Number of samples analysed for each frame
+const WINDOW_SIZE: usize = 1024;
+
+/// How much of the remembered peak level is kept every frame
+const PEAK_DECAY: f32 = 0.98;
+
+/// Frequencies (in Hz) sampled for the bass, mid and treble bands
+const BANDS: [&[f32]; 3] = [
+    &[50.0, 80.0, 125.0, 200.0],
+    &[400.0, 800.0, 1600.0, 2500.0],
+    &[4000.0, 6000.0, 9000.0, 12000.0],
+];
+
+/// Layout of a PCM stream
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    /// Samples per second, per channel
+    pub sample_rate: u32,
+    /// Number of interleaved channels
+    pub channels: u16,
+}
+
+/// Drives the lights from an audio stream: the side lights show bass, mids and treble as red,
+/// green and blue, and the logo light shows overall loudness
+pub struct Visualizer<R: Read> {
+    input: R,
+    format: Format,
+    /// Number of sample frames read from the input so far
+    position: u64,
+    /// The most recent samples, mixed down to mono
+    window: VecDeque<f32>,
+    /// Decaying peak levels of loudness and each band, used to scale them to the 0-1 range
+    peaks: [f32; 4],
+}
+
+impl<R: Read> Visualizer<R> {
+    /// Create a visualizer reading raw PCM in the given format
+    pub fn raw(input: R, format: Format) -> Self {
+        Self {
+            input,
+            format,
+            position: 0,
+            window: VecDeque::from(vec![0.0; WINDOW_SIZE]),
+            peaks: [0.0; 4],
+        }
+    }
+
+    /// Create a visualizer reading a WAV stream, taking the format from its header
+    pub fn wav(mut input: R) -> Result<Self, Error> {
+        let format = read_wav_header(&mut input)?;
+        Ok(Self::raw(input, format))
+    }
+
+    /// Read sample frames up to `target`, keeping the latest ones in the window
+    fn read_until(&mut self, target: u64) -> io::Result<()> {
+        while self.position < target {
+            let mut sum = 0.0;
+            for _ in 0..self.format.channels {
+                sum += f32::from(self.input.read_i16::<LittleEndian>()?) / 32768.0;
+            }
+            self.window.pop_front();
+            self.window.push_back(sum / f32::from(self.format.channels));
+            self.position += 1;
+        }
+        Ok(())
+    }
+
+    /// Scale a level by its decaying peak, so quiet and loud inputs both use the full range
+    fn normalize(&mut self, index: usize, level: f32) -> f32 {
+        self.peaks[index] = level.max(self.peaks[index] * PEAK_DECAY);
+        if self.peaks[index] > 1e-4 {
+            level / self.peaks[index]
+        } else {
+            0.0
+        }
+    }
+}
+
+impl<R: Read> Animation for Visualizer<R> {
+    fn frame(&mut self, time: Duration) -> Option<Frame> {
+        let target = (time.as_secs_f64() * f64::from(self.format.sample_rate)) as u64;
+        match self.read_until(target) {
+            Ok(()) => (),
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(error) => {
+                warn!("Could not read audio input: {}", error);
+                return None;
+            }
+        }
+
+        // Hann window to keep the band levels from bleeding into each other
+        let samples = self
+            .window
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s * (0.5 - 0.5 * (2.0 * PI * i as f32 / WINDOW_SIZE as f32).cos()))
+            .collect::<Vec<f32>>();
+
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+        let loudness = self.normalize(0, rms);
+
+        let mut bands = [0.0; 3];
+        for (i, frequencies) in BANDS.iter().enumerate() {
+            let level = frequencies
+                .iter()
+                .map(|f| goertzel(&samples, *f / self.format.sample_rate as f32))
+                .sum::<f32>()
+                / frequencies.len() as f32;
+            bands[i] = self.normalize(i + 1, level);
+        }
+
+        let byte = |level: f32| (level.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Some(Frame {
+            logo: Some(Color::new(byte(loudness), byte(loudness), byte(loudness))),
+            side: Some(Color::new(byte(bands[0]), byte(bands[1]), byte(bands[2]))),
+        })
+    }
+}
+
+/// Magnitude of a single frequency (in cycles per sample) in a block of samples
+fn goertzel(samples: &[f32], frequency: f32) -> f32 {
+    let coefficient = 2.0 * (2.0 * PI * frequency).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for sample in samples {
+        let s0 = sample + coefficient * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    (s1 * s1 + s2 * s2 - coefficient * s1 * s2).max(0.0).sqrt() / samples.len() as f32
+}
+
+/// Read a WAV header, leaving the input at the start of the sample data
+fn read_wav_header<R: Read>(input: &mut R) -> Result<Format, Error> {
+    let mut tag = [0u8; 4];
+
+    input.read_exact(&mut tag)?;
+    ensure!(&tag == b"RIFF", "Input is not a WAV file");
+    input.read_u32::<LittleEndian>()?;
+    input.read_exact(&mut tag)?;
+    ensure!(&tag == b"WAVE", "Input is not a WAV file");
+
+    let mut format = None;
+    loop {
+        input.read_exact(&mut tag)?;
+        let length = u64::from(input.read_u32::<LittleEndian>()?);
+        match &tag {
+            b"fmt " => {
+                let encoding = input.read_u16::<LittleEndian>()?;
+                let channels = input.read_u16::<LittleEndian>()?;
+                let sample_rate = input.read_u32::<LittleEndian>()?;
+                // Byte rate and block alignment
+                input.read_u32::<LittleEndian>()?;
+                input.read_u16::<LittleEndian>()?;
+                let bits = input.read_u16::<LittleEndian>()?;
+                ensure!(
+                    encoding == 1 && bits == 16,
+                    "Only 16 bit PCM WAV files are supported: was encoding {}, {} bits",
+                    encoding,
+                    bits,
+                );
+                ensure!(
+                    channels > 0 && sample_rate > 0,
+                    "WAV file has no channels or a sample rate of 0"
+                );
+                io::copy(
+                    &mut input.by_ref().take(length.saturating_sub(16) + length % 2),
+                    &mut io::sink(),
+                )?;
+                format = Some(Format {
+                    sample_rate,
+                    channels,
+                });
+            }
+            b"data" => {
+                return format.ok_or_else(|| format_err!("WAV data chunk came before format"));
+            }
+            _ => {
+                // Chunks are padded to an even length
+                io::copy(
+                    &mut input.by_ref().take(length + length % 2),
+                    &mut io::sink(),
+                )?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const RATE: u32 = 44100;
+
+    /// Samples of equally loud sine waves mixed together
+    fn tones(frequencies: &[f32], seconds: f32) -> Vec<f32> {
+        let amplitude = 0.8 / frequencies.len() as f32;
+        (0..(seconds * RATE as f32) as usize)
+            .map(|i| {
+                let t = i as f32 / RATE as f32;
+                frequencies
+                    .iter()
+                    .map(|f| amplitude * (2.0 * PI * f * t).sin())
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Encode samples as s16le, repeating each across the channels
+    fn pcm(samples: &[f32], channels: u16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for sample in samples {
+            let value = (sample * 32767.0) as i16;
+            for _ in 0..channels {
+                bytes.push(value as u8);
+                bytes.push((value >> 8) as u8);
+            }
+        }
+        bytes
+    }
+
+    /// Wrap s16le data in a WAV file, with an odd-length chunk before the data to skip
+    fn wav(data: &[u8], channels: u16) -> Vec<u8> {
+        let mut bytes = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0".to_vec();
+        bytes.extend_from_slice(&[channels as u8, 0]);
+        bytes.extend_from_slice(&[RATE as u8, (RATE >> 8) as u8, (RATE >> 16) as u8, 0]);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&[(channels * 2) as u8, 0, 16, 0]);
+        bytes.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        bytes.extend_from_slice(b"data");
+        let length = data.len() as u32;
+        bytes.extend_from_slice(&[
+            length as u8,
+            (length >> 8) as u8,
+            (length >> 16) as u8,
+            (length >> 24) as u8,
+        ]);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    /// Play a mix of one tone from every band so each band has a peak level, then a single
+    /// tone, and get the side color at the end
+    fn dominant_color(frequency: f32) -> Color {
+        let mut samples = tones(&[125.0, 800.0, 6000.0], 0.5);
+        samples.extend(tones(&[frequency], 0.5));
+        let format = Format {
+            sample_rate: RATE,
+            channels: 2,
+        };
+        let mut visualizer = Visualizer::raw(Cursor::new(pcm(&samples, 2)), format);
+
+        let mut last = None;
+        let mut time = Duration::from_millis(0);
+        while let Some(frame) = visualizer.frame(time) {
+            last = frame.side;
+            time += Duration::from_millis(100);
+        }
+        last.unwrap()
+    }
+
+    #[test]
+    fn bass_tone_lights_red() {
+        let color = dominant_color(125.0);
+        assert!(
+            color.red > 200 && color.green < 50 && color.blue < 50,
+            "{:?}",
+            color
+        );
+    }
+
+    #[test]
+    fn mid_tone_lights_green() {
+        let color = dominant_color(800.0);
+        assert!(
+            color.green > 200 && color.red < 50 && color.blue < 50,
+            "{:?}",
+            color
+        );
+    }
+
+    #[test]
+    fn treble_tone_lights_blue() {
+        let color = dominant_color(6000.0);
+        assert!(
+            color.blue > 200 && color.red < 50 && color.green < 50,
+            "{:?}",
+            color
+        );
+    }
+
+    #[test]
+    fn silence_is_dark() {
+        let format = Format {
+            sample_rate: RATE,
+            channels: 1,
+        };
+        let mut visualizer = Visualizer::raw(Cursor::new(pcm(&[0.0; 8820], 1)), format);
+        let frame = visualizer.frame(Duration::from_millis(100)).unwrap();
+        assert_eq!(frame.logo, Some(Color::new(0, 0, 0)));
+        assert_eq!(frame.side, Some(Color::new(0, 0, 0)));
+    }
+
+    #[test]
+    fn input_ending_ends_animation() {
+        let format = Format {
+            sample_rate: RATE,
+            channels: 2,
+        };
+        let mut visualizer = Visualizer::raw(Cursor::new(pcm(&[0.0; 100], 2)), format);
+        assert!(visualizer.frame(Duration::from_millis(1)).is_some());
+        assert!(visualizer.frame(Duration::from_millis(100)).is_none());
+    }
+
+    #[test]
+    fn wav_header() {
+        let bytes = wav(&pcm(&tones(&[800.0], 0.2), 2), 2);
+        let mut input = Cursor::new(bytes);
+        let format = read_wav_header(&mut input).unwrap();
+        assert_eq!(format.sample_rate, RATE);
+        assert_eq!(format.channels, 2);
+        // Left at the start of the samples
+        assert_eq!(input.read_i16::<LittleEndian>().unwrap(), 0);
+    }
+
+    #[test]
+    fn wav_plays_like_raw() {
+        let data = pcm(&tones(&[800.0], 0.2), 2);
+        let format = Format {
+            sample_rate: RATE,
+            channels: 2,
+        };
+        let mut raw = Visualizer::raw(Cursor::new(data.clone()), format);
+        let mut wav = Visualizer::wav(Cursor::new(wav(&data, 2))).unwrap();
+        let time = Duration::from_millis(100);
+        assert_eq!(raw.frame(time), wav.frame(time));
+    }
+
+    #[test]
+    fn rejects_other_wav_encodings() {
+        let mut bytes = wav(&[], 2);
+        // 8 bits per sample
+        bytes[34] = 8;
+        assert!(read_wav_header(&mut Cursor::new(bytes)).is_err());
+        assert!(read_wav_header(&mut Cursor::new(b"RIFX".to_vec())).is_err());
+    }
+}
@@ -1,61 +1,282 @@
 //! Battery-related code and stuff
 
 use byteorder::{BigEndian, ByteOrder};
+use failure::Error;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+use serde_json;
 use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use xdg;
 use FromBytesWithDevice;
 use StaticDeviceMatch;
+use {FEATURE_BATTERY, FEATURE_BATTERY_VOLTAGE, FEATURE_UNIFIED_BATTERY};
 
 macro_rules! mapset {
-    ($maps:expr, $pid:expr, $str:expr) => {
+    ($maps:expr, $str:expr) => {
         {
             $maps.insert(
-                ($pid, ChargingStatus::Discharging),
-                make_map(include_str!(concat!("maps/", $str, "/discharging.csv"))));
+                ($str.to_string(), ChargingStatus::Discharging),
+                make_curve(include_str!(concat!("maps/", $str, "/discharging.csv"))));
             $maps.insert(
-                ($pid, ChargingStatus::Charging(false)),
-                make_map(include_str!(concat!("maps/", $str, "/charging_ascending.csv"))));
+                ($str.to_string(), ChargingStatus::Charging(false)),
+                make_curve(include_str!(concat!("maps/", $str, "/charging_ascending.csv"))));
             $maps.insert(
-                ($pid, ChargingStatus::Charging(true)),
-                make_map(include_str!(concat!("maps/", $str, "/charging_descending.csv"))));
-            $maps.insert(
-                ($pid, ChargingStatus::Full), {
-                let mut map = BTreeMap::new();
-                map.insert(0, 100.0);
-                map.insert(1, 100.0);
-                map
-            });
+                ($str.to_string(), ChargingStatus::Charging(true)),
+                make_curve(include_str!(concat!("maps/", $str, "/charging_descending.csv"))));
         }
     };
 }
 
 lazy_static! {
-    static ref VOLTAGE_MAPS: HashMap<(u16, ChargingStatus), BTreeMap<isize, f32>> = {
+    /// Built-in curves by curve set, overridden by any found in the user's data directory
+    static ref VOLTAGE_MAPS: RwLock<HashMap<(String, ChargingStatus), VoltageCurve>> = {
         let mut maps = HashMap::new();
 
-        fn make_map(input: &str) -> BTreeMap<isize, f32> {
-            let mut map = BTreeMap::new();
-
-            for mut split in input
-                .split('\n')
-                .filter(|line| !line.is_empty())
-                .map(|line| line.splitn(2, ','))
-            {
-                map.insert(
-                    split.next().unwrap().parse::<isize>().unwrap(),
-                    split.next().unwrap().parse::<f32>().unwrap(),
-                );
+        fn make_curve(input: &str) -> VoltageCurve {
+            input.parse().unwrap()
+        };
+
+        mapset!(maps, "0A5B");
+        mapset!(maps, "0A66");
+
+        if let Some(dir) = user_curves_dir().filter(|dir| dir.exists()) {
+            // Broken user files shouldn't keep the headset from working, so only complain
+            match read_curves(&dir, &mut maps) {
+                Ok(skipped) => {
+                    for error in skipped {
+                        error!("Skipped battery curve: {}", error);
+                    }
+                }
+                Err(error) => error!("Could not load battery curves: {}", error),
             }
+        }
+
+        RwLock::new(maps)
+    };
+}
+
+/// Curve files looked for in each device's directory, and the status they are used for
+const CURVE_FILES: &[(&str, ChargingStatus)] = &[
+    ("discharging.csv", ChargingStatus::Discharging),
+    ("charging_ascending.csv", ChargingStatus::Charging(false)),
+    ("charging_descending.csv", ChargingStatus::Charging(true)),
+];
+
+/// Name of the file holding the curve for a charging status, if it has one
+pub fn curve_file_name(charging_status: ChargingStatus) -> Option<&'static str> {
+    CURVE_FILES
+        .iter()
+        .find(|&&(_, status)| status == charging_status)
+        .map(|&(name, _)| name)
+}
+
+/// Directory that user battery curves are loaded from automatically,
+/// `$XDG_DATA_HOME/g933-utils/curves`
+pub fn user_curves_dir() -> Option<PathBuf> {
+    xdg::data_dir().map(|dir| dir.join("curves"))
+}
 
-            map
+/// Load battery curves from a directory, replacing the ones already known. Curves go in a
+/// subdirectory per curve set, which is named after the device's PID in hex (like `0A5B`)
+/// unless the device database says otherwise, as any of `discharging.csv`,
+/// `charging_ascending.csv` and `charging_descending.csv`
+pub fn load_curves(dir: &Path) -> Result<(), Error> {
+    let mut curves = HashMap::new();
+    if let Some(error) = read_curves(dir, &mut curves)?.into_iter().next() {
+        return Err(error);
+    }
+    VOLTAGE_MAPS.write().unwrap().extend(curves);
+    Ok(())
+}
+
+/// Read curves from a directory into `maps`. Curves that can't be read are skipped, and the
+/// errors for them returned
+fn read_curves(
+    dir: &Path,
+    maps: &mut HashMap<(String, ChargingStatus), VoltageCurve>,
+) -> Result<Vec<Error>, Error> {
+    let entries = fs::read_dir(dir).map_err(|error| format_err!("{}: {}", dir.display(), error))?;
+    let mut skipped = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let set = match path.file_name().and_then(|name| name.to_str()) {
+            Some(set) => set,
+            None => {
+                skipped.push(format_err!(
+                    "{}: directory name is not valid UTF-8",
+                    path.display()
+                ));
+                continue;
+            }
         };
 
-        mapset!(maps, 0x0A5B, "0A5B");
-        mapset!(maps, 0x0A66, "0A66");
+        for &(name, charging_status) in CURVE_FILES {
+            let file = path.join(name);
+            if !file.exists() {
+                continue;
+            }
+            let curve = fs::read_to_string(&file)
+                .map_err(Error::from)
+                .and_then(|contents| contents.parse::<VoltageCurve>());
+            match curve {
+                Ok(curve) => {
+                    info!("Loaded battery curve {}", file.display());
+                    maps.insert((set.to_string(), charging_status), curve);
+                }
+                Err(error) => skipped.push(format_err!("{}: {}", file.display(), error)),
+            }
+        }
+    }
+
+    Ok(skipped)
+}
 
+/// Typical jitter (in mV) between successive voltage readings at the same charge
+const VOLTAGE_JITTER: u16 = 10;
 
-        maps
-    };
+/// Mapping from battery voltage (in mV) to charge percentage
+#[derive(Debug, Clone)]
+pub struct VoltageCurve {
+    points: BTreeMap<u16, f32>,
+}
+
+impl VoltageCurve {
+    /// Build a curve from `(voltage, charge)` points in any order. Points with the same voltage
+    /// are averaged, and the charges are smoothed so they only ever rise or only ever fall with
+    /// the voltage, whichever way most of the curve goes
+    pub fn new(points: &[(u16, f32)]) -> Result<Self, Error> {
+        ensure!(!points.is_empty(), "Voltage curve has no points");
+
+        // Average out duplicate voltages, remembering how many points went into each
+        let mut merged: BTreeMap<u16, (f32, f32)> = BTreeMap::new();
+        for &(voltage, charge) in points {
+            ensure!(
+                (0.0..=100.0).contains(&charge),
+                "Charge for {} mV is out of range: was {}",
+                voltage,
+                charge
+            );
+            let entry = merged.entry(voltage).or_insert((0.0, 0.0));
+            entry.0 += charge;
+            entry.1 += 1.0;
+        }
+        let mut blocks = merged
+            .into_iter()
+            .map(|(voltage, (sum, count))| (vec![voltage], sum / count, count))
+            .collect::<Vec<_>>();
+
+        let rising = blocks[blocks.len() - 1].1 >= blocks[0].1;
+        let sign = if rising { 1.0 } else { -1.0 };
+
+        // Pool adjacent points that go the wrong way into their weighted average
+        let mut pooled: Vec<(Vec<u16>, f32, f32)> = Vec::with_capacity(blocks.len());
+        for block in blocks.drain(..) {
+            let (mut voltages, mut charge, mut weight) = block;
+            while let Some(last) = pooled.pop() {
+                if sign * last.1 <= sign * charge {
+                    pooled.push(last);
+                    break;
+                }
+                charge = (last.1 * last.2 + charge * weight) / (last.2 + weight);
+                weight += last.2;
+                let mut last_voltages = last.0;
+                last_voltages.extend(voltages);
+                voltages = last_voltages;
+            }
+            pooled.push((voltages, charge, weight));
+        }
+
+        Ok(Self {
+            points: pooled
+                .into_iter()
+                .flat_map(|(voltages, charge, _)| voltages.into_iter().map(move |v| (v, charge)))
+                .collect(),
+        })
+    }
+
+    /// Estimate the charge percentage for a voltage by interpolating between the points on
+    /// either side of it. Voltages outside of the curve get the charge of the nearest end
+    pub fn charge(&self, voltage: u16) -> f32 {
+        let below = self.points.range(..=voltage).next_back();
+        let above = self.points.range(voltage..).next();
+
+        match (below, above) {
+            (Some((&v0, &c0)), Some((&v1, &c1))) if v0 != v1 => {
+                c0 + (c1 - c0) * f32::from(voltage - v0) / f32::from(v1 - v0)
+            }
+            (Some((_, &charge)), _) | (_, Some((_, &charge))) => charge,
+            (None, None) => unreachable!("Voltage curves always have points"),
+        }
+    }
+
+    /// Estimate the charge percentage for a voltage along with how far off it could be (in
+    /// percentage points either way), based on how much the charge changes over the usual
+    /// jitter in voltage readings around it
+    pub fn estimate(&self, voltage: u16) -> (f32, f32) {
+        let low = self.charge(voltage.saturating_sub(VOLTAGE_JITTER));
+        let high = self.charge(voltage.saturating_add(VOLTAGE_JITTER));
+        (self.charge(voltage), (high - low).abs() / 2.0)
+    }
+
+    /// Points of the curve after cleaning up, as `(voltage, charge)` in order of voltage
+    pub fn points(&self) -> Vec<(u16, f32)> {
+        self.points.iter().map(|(v, c)| (*v, *c)).collect()
+    }
+}
+
+impl FromStr for VoltageCurve {
+    type Err = Error;
+
+    /// Parse a curve from CSV lines of `voltage,charge`
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut points = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut split = line.splitn(2, ',');
+            let point = match (split.next(), split.next()) {
+                (Some(voltage), Some(charge)) => voltage
+                    .trim()
+                    .parse::<u16>()
+                    .ok()
+                    .and_then(|v| charge.trim().parse::<f32>().ok().map(|c| (v, c))),
+                _ => None,
+            };
+            match point {
+                Some(point) => points.push(point),
+                None => bail!(
+                    "Line {}: expected `voltage,charge`, got `{}`",
+                    number + 1,
+                    line
+                ),
+            }
+        }
+        Self::new(&points)
+    }
+}
+
+impl fmt::Display for VoltageCurve {
+    /// Write the curve as CSV lines of `voltage,charge`, the same format it is parsed from
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (voltage, charge) in &self.points {
+            writeln!(f, "{},{:.2}", voltage, charge)?;
+        }
+        Ok(())
+    }
 }
 
 /// Charging status
@@ -65,8 +286,80 @@ pub enum ChargingStatus {
     Discharging,
     /// Battery is charging - contains false when ascending, true when descending
     Charging(bool),
+    /// Battery is charging, but slower than it should
+    SlowCharging,
     /// Battery is full
     Full,
+    /// Charging stopped because something went wrong
+    ChargingError,
+    /// Status code we don't know the meaning of
+    Unknown(u8),
+}
+
+impl ChargingStatus {
+    /// Short name of the status, like `charging_ascending`. Files use the `Display` form, which
+    /// also keeps the code of unknown statuses
+    pub fn name(self) -> &'static str {
+        match self {
+            ChargingStatus::Discharging => "discharging",
+            ChargingStatus::Charging(false) => "charging_ascending",
+            ChargingStatus::Charging(true) => "charging_descending",
+            ChargingStatus::SlowCharging => "slow_charging",
+            ChargingStatus::Full => "full",
+            ChargingStatus::ChargingError => "charging_error",
+            ChargingStatus::Unknown(_) => "unknown",
+        }
+    }
+
+    /// Whether the charger is plugged in, if the status tells us
+    pub fn charger_connected(self) -> Option<bool> {
+        match self {
+            ChargingStatus::Discharging => Some(false),
+            ChargingStatus::Unknown(_) => None,
+            _ => Some(true),
+        }
+    }
+}
+
+impl FromStr for ChargingStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "discharging" => Ok(ChargingStatus::Discharging),
+            "charging_ascending" => Ok(ChargingStatus::Charging(false)),
+            "charging_descending" => Ok(ChargingStatus::Charging(true)),
+            "slow_charging" => Ok(ChargingStatus::SlowCharging),
+            "full" => Ok(ChargingStatus::Full),
+            "charging_error" => Ok(ChargingStatus::ChargingError),
+            s if s.starts_with("unknown_") => Ok(ChargingStatus::Unknown(s[8..].parse()?)),
+            s => bail!("Invalid charging status: {}", s),
+        }
+    }
+}
+
+impl fmt::Display for ChargingStatus {
+    /// Write the status as used in files, like `charging_ascending` or `unknown_9`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChargingStatus::Unknown(code) => write!(f, "unknown_{}", code),
+            status => f.write_str(status.name()),
+        }
+    }
+}
+
+impl Serialize for ChargingStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChargingStatus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
 }
 
 /// Battery status
@@ -74,72 +367,591 @@ pub enum ChargingStatus {
 pub struct BatteryStatus {
     /// Charging status
     pub charging_status: ChargingStatus,
-    /// Battery voltage
-    pub voltage: u16,
-    /// Charge percentage
-    pub charge: f32,
+    /// Battery voltage, if the device reports it
+    pub voltage: Option<u16>,
+    /// Charge percentage, reported by the device or looked up on a curve for its voltage
+    pub charge: Option<f32>,
+    /// How far off the charge percentage could be, in percentage points either way
+    pub uncertainty: Option<f32>,
 }
 
-impl FromBytesWithDevice for BatteryStatus {
-    fn from_bytes(dev: StaticDeviceMatch, bytes: &[u8]) -> Option<Self> {
-        let charging_status = match bytes[2] {
-            1 => ChargingStatus::Discharging,
-            3 => ChargingStatus::Charging(false), // TODO: implement check for ascending/descending
-            7 => ChargingStatus::Full,
-            s => panic!("Encountered unknown charging status: {}", s),
+impl BatteryStatus {
+    /// Build a battery status from a voltage reading, looking up the charge on the device's curve
+    /// for the charging status if there is one. A full battery is always at 100%
+    pub fn new(dev: StaticDeviceMatch, charging_status: ChargingStatus, voltage: u16) -> Self {
+        let estimate = match charging_status {
+            ChargingStatus::Full => Some((100.0, 0.0)),
+            _ => VOLTAGE_MAPS
+                .read()
+                .unwrap()
+                .get(&(dev.model.curves.clone(), charging_status))
+                .map(|curve| curve.estimate(voltage)),
         };
 
-        debug!("Charging status: {:?}", charging_status);
+        debug!("Charge: {:?}", estimate);
 
-        let voltage = BigEndian::read_u16(&bytes[0..2]) as isize;
+        Self {
+            charging_status,
+            voltage: Some(voltage),
+            charge: estimate.map(|(charge, _)| charge),
+            uncertainty: estimate.map(|(_, uncertainty)| uncertainty),
+        }
+    }
+
+    /// Build a battery status from a reading, using the level the device reported if there is
+    /// one, or the device's curve for the voltage otherwise
+    pub fn from_reading(dev: StaticDeviceMatch, reading: Reading) -> Self {
+        match (reading.level, reading.voltage) {
+            (None, Some(voltage)) => Self::new(dev, reading.charging_status, voltage),
+            (level, voltage) => Self {
+                charging_status: reading.charging_status,
+                voltage,
+                charge: level.map(|(charge, _)| charge),
+                uncertainty: level.map(|(_, uncertainty)| uncertainty),
+            },
+        }
+    }
+}
+
+impl FromBytesWithDevice for BatteryStatus {
+    /// Charging is always taken to be ascending, use a `ChargePhase` to tell the phases apart
+    fn from_bytes(dev: StaticDeviceMatch, bytes: &[u8]) -> Option<Self> {
+        let (charging_status, voltage) = read_status(bytes);
+        Some(Self::new(dev, charging_status, voltage))
+    }
+}
 
-        debug!("Voltage: {}", voltage);
+/// Voltage drop below the peak (in mV) taken to mean charging has moved on to the descending
+/// phase, so noise in the readings doesn't switch phases early
+const DESCENDING_THRESHOLD: u16 = 5;
 
-        let key = &(dev.pid, charging_status);
-        if !VOLTAGE_MAPS.contains_key(key) {
-            return None;
+/// Follows the voltage across successive readings to tell whether charging is still ascending
+/// (constant current) or has reached the descending (constant voltage) phase
+#[derive(Debug, Clone, Default)]
+pub struct ChargePhase {
+    /// Highest voltage seen since charging started
+    peak: Option<u16>,
+    /// Whether the voltage has started dropping since charging started
+    descending: bool,
+}
+
+impl ChargePhase {
+    /// Pick up following the phase from earlier readings, oldest first, such as a saved history.
+    /// This lets a one-off reading tell the phases apart too
+    pub fn resume<'a, I: IntoIterator<Item = &'a Sample>>(samples: I) -> Self {
+        let mut phase = Self::default();
+        for sample in samples {
+            match sample.voltage {
+                Some(voltage) => {
+                    phase.track(sample.charging_status, voltage);
+                }
+                None => phase = Self::default(),
+            }
+            // The peak may be gone from the readings, but descending stays descending
+            if sample.charging_status == ChargingStatus::Charging(true) {
+                phase.descending = true;
+            }
         }
-        let map = &VOLTAGE_MAPS[key];
+        phase
+    }
 
-        let closest_voltages = {
-            let mut closest = (isize::max_value(), isize::max_value());
-            for v in map.keys() {
-                // Insert in reverse to get them in increasing order
-                if (*v - voltage).abs() < (closest.1 - voltage).abs() {
-                    closest.0 = closest.1;
-                    closest.1 = *v;
+    /// Take a new reading into account, returning the charging status with the phase filled in
+    pub fn track(&mut self, charging_status: ChargingStatus, voltage: u16) -> ChargingStatus {
+        match charging_status {
+            ChargingStatus::Charging(_) => {
+                let peak = self.peak.map_or(voltage, |peak| peak.max(voltage));
+                if peak - voltage >= DESCENDING_THRESHOLD {
+                    self.descending = true;
                 }
+                self.peak = Some(peak);
+
+                debug!(
+                    "Charging peak voltage: {}, descending: {}",
+                    peak, self.descending
+                );
+
+                ChargingStatus::Charging(self.descending)
+            }
+            _ => {
+                // Charging starts over from the ascending phase next time
+                *self = Self::default();
+                charging_status
+            }
+        }
+    }
+}
+
+/// Change in power state reported by the headset on its own
+#[derive(Debug)]
+pub enum PowerEvent {
+    /// The charger was plugged in
+    ChargerConnected,
+    /// The charger was unplugged
+    ChargerDisconnected,
+    /// The headset is turning off
+    PoweredOff,
+    /// The headset reported a new battery status
+    StatusChanged(BatteryStatus),
+}
+
+/// Decode a battery notification into a charging status and voltage, or `None` if the headset
+/// is turning off
+pub fn read_notification(bytes: &[u8]) -> Option<(ChargingStatus, u16)> {
+    if bytes[..3] == [0x00, 0x00, 0x00] {
+        None
+    } else {
+        Some(read_status(bytes))
+    }
+}
+
+/// Decode the charging status and voltage from a battery status response
+pub fn read_status(bytes: &[u8]) -> (ChargingStatus, u16) {
+    let charging_status = match bytes[2] {
+        1 => ChargingStatus::Discharging,
+        3 => ChargingStatus::Charging(false),
+        7 => ChargingStatus::Full,
+        s => ChargingStatus::Unknown(s),
+    };
+
+    debug!("Charging status: {:?}", charging_status);
+
+    let voltage = BigEndian::read_u16(&bytes[0..2]);
+
+    debug!("Voltage: {}", voltage);
+
+    (charging_status, voltage)
+}
+
+/// Feature a device reports its battery through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatteryFeature {
+    /// Headset battery status (0x1f20), as on the G933
+    Headset,
+    /// Unified battery (0x1004)
+    Unified {
+        /// Whether it reports an exact charge percentage rather than only a coarse level
+        state_of_charge: bool,
+    },
+    /// Battery voltage (0x1001)
+    Voltage,
+}
+
+impl BatteryFeature {
+    /// Feature ID
+    pub fn id(self) -> u16 {
+        match self {
+            BatteryFeature::Headset => FEATURE_BATTERY,
+            BatteryFeature::Unified { .. } => FEATURE_UNIFIED_BATTERY,
+            BatteryFeature::Voltage => FEATURE_BATTERY_VOLTAGE,
+        }
+    }
+
+    /// Function (with software ID) that gets the battery status
+    pub fn status_function(self) -> u8 {
+        match self {
+            BatteryFeature::Headset | BatteryFeature::Voltage => 0x01,
+            BatteryFeature::Unified { .. } => 0x11,
+        }
+    }
+
+    /// Decode a battery status response
+    pub fn read_status(self, bytes: &[u8]) -> Reading {
+        match self {
+            BatteryFeature::Headset => {
+                let (charging_status, voltage) = read_status(bytes);
+                Reading::voltage(charging_status, voltage)
             }
-            closest
+            BatteryFeature::Unified { state_of_charge } => {
+                read_unified_status(bytes, state_of_charge)
+            }
+            BatteryFeature::Voltage => {
+                let (charging_status, voltage) = read_voltage_status(bytes);
+                Reading::voltage(charging_status, voltage)
+            }
+        }
+    }
+
+    /// Decode a battery notification, or `None` if the headset is turning off. Only the headset
+    /// feature is known to say so
+    pub fn read_notification(self, bytes: &[u8]) -> Option<Reading> {
+        match self {
+            BatteryFeature::Headset => read_notification(bytes)
+                .map(|(charging_status, voltage)| Reading::voltage(charging_status, voltage)),
+            _ => Some(self.read_status(bytes)),
+        }
+    }
+}
+
+/// Battery status as the device reported it, before the charge is looked up from the voltage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    /// Charging status
+    pub charging_status: ChargingStatus,
+    /// Battery voltage, if the device reports it
+    pub voltage: Option<u16>,
+    /// Charge percentage and how far off it could be, if the device reports it
+    pub level: Option<(f32, f32)>,
+}
+
+impl Reading {
+    /// Reading of a device that reports its voltage only
+    pub fn voltage(charging_status: ChargingStatus, voltage: u16) -> Self {
+        Self {
+            charging_status,
+            voltage: Some(voltage),
+            level: None,
+        }
+    }
+}
+
+/// Decode the charging status and voltage from a battery voltage (0x1001) response
+pub fn read_voltage_status(bytes: &[u8]) -> (ChargingStatus, u16) {
+    let flags = bytes[2];
+    // Bit 7 is set while the charger is plugged in, bit 4 while it is trickle charging
+    let charging_status = if flags & 0x80 == 0 {
+        ChargingStatus::Discharging
+    } else {
+        match flags & 0x07 {
+            0 if flags & 0x10 != 0 => ChargingStatus::SlowCharging,
+            0 => ChargingStatus::Charging(false),
+            1 => ChargingStatus::Full,
+            2 => ChargingStatus::ChargingError,
+            status => ChargingStatus::Unknown(status),
+        }
+    };
+
+    debug!("Charging status: {:?}", charging_status);
+
+    let voltage = BigEndian::read_u16(&bytes[0..2]);
+
+    debug!("Voltage: {}", voltage);
+
+    (charging_status, voltage)
+}
+
+/// Charge percentage and uncertainty each unified battery level flag stands for, going by the
+/// ranges other HID++ devices use. Only used when the device doesn't report an exact percentage
+const UNIFIED_LEVELS: [(u8, (f32, f32)); 4] = [
+    (0x08, (90.0, 10.0)),
+    (0x04, (55.0, 25.0)),
+    (0x02, (20.0, 10.0)),
+    (0x01, (5.0, 5.0)),
+];
+
+/// Decode a unified battery (0x1004) status response
+pub fn read_unified_status(bytes: &[u8], state_of_charge: bool) -> Reading {
+    let charging_status = match bytes[2] {
+        0 => ChargingStatus::Discharging,
+        1 => ChargingStatus::Charging(false),
+        2 => ChargingStatus::SlowCharging,
+        3 => ChargingStatus::Full,
+        4 => ChargingStatus::ChargingError,
+        s => ChargingStatus::Unknown(s),
+    };
+
+    debug!("Charging status: {:?}", charging_status);
+
+    let level = if state_of_charge {
+        Some((f32::from(bytes[0]), 0.0))
+    } else {
+        UNIFIED_LEVELS
+            .iter()
+            .find(|&&(flag, _)| bytes[1] & flag != 0)
+            .map(|&(_, level)| level)
+    };
+
+    debug!("Level: {:?}", level);
+
+    Reading {
+        charging_status,
+        voltage: None,
+        level,
+    }
+}
+
+/// A battery reading taken at some point in time
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sample {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    /// Battery voltage, if the device reports it
+    pub voltage: Option<u16>,
+    /// Charging status
+    #[serde(rename = "status")]
+    pub charging_status: ChargingStatus,
+    /// Charge percentage estimated at the time, if there was a curve for it
+    pub charge: Option<f32>,
+}
+
+impl Sample {
+    /// Take a sample at the current time
+    pub fn now(charging_status: ChargingStatus, voltage: Option<u16>, charge: Option<f32>) -> Self {
+        Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            voltage,
+            charging_status,
+            charge,
+        }
+    }
+
+    /// Format the sample as a JSON object on a single line
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Parse a sample from a JSON object as written by `to_json`
+    pub fn from_json(s: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+impl fmt::Display for Sample {
+    /// Write the sample as a CSV line of `time,voltage,status,charge`, leaving the voltage or
+    /// charge empty if there is none
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},", self.time)?;
+        if let Some(voltage) = self.voltage {
+            write!(f, "{}", voltage)?;
+        }
+        write!(f, ",{},", self.charging_status)?;
+        if let Some(charge) = self.charge {
+            write!(f, "{:.2}", charge)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Sample {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let fields = s.trim().split(',').collect::<Vec<_>>();
+        ensure!(
+            fields.len() == 4,
+            "Expected `time,voltage,status,charge`, got `{}`",
+            s.trim()
+        );
+        Ok(Self {
+            time: fields[0].parse()?,
+            voltage: match fields[1] {
+                "" => None,
+                voltage => Some(voltage.parse()?),
+            },
+            charging_status: fields[2].parse()?,
+            charge: match fields[3] {
+                "" => None,
+                charge => Some(charge.parse()?),
+            },
+        })
+    }
+}
+
+/// Read samples from CSV lines as written by `Sample`'s `Display` implementation, or JSON lines
+/// as written by `Sample::to_json`
+pub fn read_samples<R: BufRead>(input: R) -> Result<Vec<Sample>, Error> {
+    let mut samples = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let sample = if line.trim_start().starts_with('{') {
+            Sample::from_json(&line)
+        } else {
+            line.parse()
         };
+        samples.push(sample.map_err(|error| format_err!("Line {}: {}", number + 1, error))?);
+    }
+    Ok(samples)
+}
 
-        debug!(
-            "Closest mapped voltages: {}, {}",
-            closest_voltages.0, closest_voltages.1
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_duplicate_voltages() {
+        let curve = "4042,100.00\n4042,100.00\n4042,100.00\n4027,100.00\n4011,99.91\n"
+            .parse::<VoltageCurve>()
+            .unwrap();
+        assert_eq!(
+            curve.points(),
+            vec![(4011, 99.91), (4027, 100.0), (4042, 100.0)]
         );
 
-        let closest_charges = (map[&closest_voltages.0], map[&closest_voltages.1]);
+        let curve = VoltageCurve::new(&[(3900, 60.0), (3900, 70.0), (3800, 40.0)]).unwrap();
+        assert_eq!(curve.points(), vec![(3800, 40.0), (3900, 65.0)]);
+    }
 
-        debug!(
-            "Corresponding charges: {}, {}",
-            closest_charges.0, closest_charges.1
+    #[test]
+    fn pools_points_going_the_wrong_way() {
+        // 3850 mV reads as more charged than 3900 mV, so the two are pooled
+        let curve = VoltageCurve::new(&[
+            (3700, 10.0),
+            (3800, 30.0),
+            (3850, 60.0),
+            (3900, 50.0),
+            (4000, 90.0),
+        ])
+        .unwrap();
+        assert_eq!(
+            curve.points(),
+            vec![
+                (3700, 10.0),
+                (3800, 30.0),
+                (3850, 55.0),
+                (3900, 55.0),
+                (4000, 90.0)
+            ]
         );
+    }
 
-        let slope = ((voltage - closest_voltages.0) as f32)
-            / ((closest_voltages.1 - closest_voltages.0) as f32);
+    #[test]
+    fn interpolates_between_points() {
+        let curve = VoltageCurve::new(&[(3600, 0.0), (3800, 50.0), (4000, 100.0)]).unwrap();
+        assert_eq!(curve.charge(3800), 50.0);
+        assert_eq!(curve.charge(3700), 25.0);
+        assert_eq!(curve.charge(3950), 87.5);
+    }
 
-        debug!("Voltage graph slope: {}", slope);
+    #[test]
+    fn clamps_voltages_outside_the_curve() {
+        let curve = VoltageCurve::new(&[(3600, 5.0), (4000, 100.0)]).unwrap();
+        assert_eq!(curve.charge(3000), 5.0);
+        assert_eq!(curve.charge(4200), 100.0);
+        // Close to the ends only the side on the curve counts towards the uncertainty
+        let (charge, uncertainty) = curve.estimate(4200);
+        assert_eq!((charge, uncertainty), (100.0, 0.0));
+    }
 
-        let charge = (((closest_charges.1 - closest_charges.0) * slope) + closest_charges.0)
-            .max(0.0)
-            .min(100.0);
+    #[test]
+    fn rejects_bad_curves() {
+        assert!(VoltageCurve::new(&[]).is_err());
+        assert!(VoltageCurve::new(&[(3800, 101.0)]).is_err());
+        assert!("3800;50".parse::<VoltageCurve>().is_err());
+    }
 
-        debug!("Charge: {}", charge);
+    #[test]
+    fn descends_once_voltage_drops_from_peak() {
+        let mut phase = ChargePhase::default();
+        let charging = ChargingStatus::Charging(false);
+        assert_eq!(phase.track(charging, 4000), ChargingStatus::Charging(false));
+        assert_eq!(phase.track(charging, 4100), ChargingStatus::Charging(false));
+        // Less than 5 mV below the peak is jitter
+        assert_eq!(phase.track(charging, 4096), ChargingStatus::Charging(false));
+        assert_eq!(phase.track(charging, 4095), ChargingStatus::Charging(true));
+        // Once descending, it stays that way while charging
+        assert_eq!(phase.track(charging, 4110), ChargingStatus::Charging(true));
 
-        Some(Self {
+        // Unplugging starts over
+        assert_eq!(
+            phase.track(ChargingStatus::Discharging, 4000),
+            ChargingStatus::Discharging
+        );
+        assert_eq!(phase.track(charging, 4000), ChargingStatus::Charging(false));
+    }
+
+    #[test]
+    fn resumes_from_samples() {
+        let sample = |charging_status, voltage| Sample {
+            time: 0,
+            voltage: Some(voltage),
             charging_status,
-            voltage: voltage as u16,
-            charge,
-        })
+            charge: None,
+        };
+
+        let mut phase = ChargePhase::resume(&[
+            sample(ChargingStatus::Charging(false), 4100),
+            sample(ChargingStatus::Charging(false), 4098),
+        ]);
+        assert_eq!(
+            phase.track(ChargingStatus::Charging(false), 4094),
+            ChargingStatus::Charging(true)
+        );
+
+        // Saved as descending, even without the peak in the samples
+        let mut phase = ChargePhase::resume(&[sample(ChargingStatus::Charging(true), 4090)]);
+        assert_eq!(
+            phase.track(ChargingStatus::Charging(false), 4095),
+            ChargingStatus::Charging(true)
+        );
+
+        let mut phase = ChargePhase::resume(&[
+            sample(ChargingStatus::Charging(true), 4090),
+            sample(ChargingStatus::Discharging, 4000),
+        ]);
+        assert_eq!(
+            phase.track(ChargingStatus::Charging(false), 4000),
+            ChargingStatus::Charging(false)
+        );
+    }
+
+    #[test]
+    fn reads_voltage_status() {
+        assert_eq!(
+            read_voltage_status(&[0x0f, 0xab, 0x00]),
+            (ChargingStatus::Discharging, 4011)
+        );
+        assert_eq!(
+            read_voltage_status(&[0x0f, 0xab, 0x90]).0,
+            ChargingStatus::SlowCharging
+        );
+        assert_eq!(
+            read_voltage_status(&[0x0f, 0xab, 0x81]).0,
+            ChargingStatus::Full
+        );
+        // Only the status bits make it into unknown statuses
+        assert_eq!(
+            read_voltage_status(&[0x0f, 0xab, 0x95]).0,
+            ChargingStatus::Unknown(5)
+        );
+    }
+
+    #[test]
+    fn round_trips_samples() {
+        let samples = [
+            Sample {
+                time: 1_500_000_000,
+                voltage: Some(4011),
+                charging_status: ChargingStatus::Charging(true),
+                charge: Some(99.91),
+            },
+            Sample {
+                time: 1_500_000_060,
+                voltage: None,
+                charging_status: ChargingStatus::Unknown(9),
+                charge: None,
+            },
+        ];
+
+        let csv = samples
+            .iter()
+            .map(|sample| format!("{}\n", sample))
+            .collect::<String>();
+        assert_eq!(read_samples(csv.as_bytes()).unwrap(), samples);
+
+        let json = samples
+            .iter()
+            .map(|sample| format!("{}\n", sample.to_json().unwrap()))
+            .collect::<String>();
+        assert_eq!(read_samples(json.as_bytes()).unwrap(), samples);
+    }
+
+    #[test]
+    fn reads_json_samples() {
+        let sample =
+            Sample::from_json(r#"{"time":60,"voltage":3900,"status":"unknown_9","charge":null}"#)
+                .unwrap();
+        assert_eq!(sample.voltage, Some(3900));
+        assert_eq!(sample.charging_status, ChargingStatus::Unknown(9));
+        assert_eq!(sample.charge, None);
+
+        assert!(Sample::from_json(r#"{"time":60,"status":"full","extra":1}"#).is_err());
+        assert!(Sample::from_json(r#"{"time":60,"status":"sideways"}"#).is_err());
+        assert!(read_samples(&b"60,3900,full,\n{\"time\":61}\n"[..]).is_err());
     }
 }
@@ -0,0 +1,124 @@
+//! Fit battery curves from a recorded charge and discharge cycle
+//!
+//! The battery is assumed to charge and discharge at a steady rate, so the charge at each sample
+//! is taken from how far through its charging or discharging run it was. For this to work the
+//! recording has to cover a discharge from full until the headset turns itself off, and a charge
+//! from empty until it reports being full.
+
+use failure::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use battery::{curve_file_name, ChargingStatus, Sample, VoltageCurve};
+
+/// Gap between samples (in seconds) that is taken to mean the headset was off or unplugged in
+/// between, which splits a run in two
+const MAX_GAP: u64 = 15 * 60;
+
+/// Fewest samples a run needs to fit a curve from
+const MIN_SAMPLES: usize = 10;
+
+/// Fit curves for each charging status that the samples cover. Samples should be in the order
+/// they were taken, any without a voltage are skipped
+pub fn fit(samples: &[Sample]) -> Vec<(ChargingStatus, VoltageCurve)> {
+    let samples = &samples
+        .iter()
+        .filter(|sample| sample.voltage.is_some())
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut curves = Vec::new();
+
+    if let Some(run) = longest_run(samples, |status| status == ChargingStatus::Discharging) {
+        let points = spread(run, 100.0, 0.0);
+        curves.extend(
+            VoltageCurve::new(&points)
+                .ok()
+                .map(|curve| (ChargingStatus::Discharging, curve)),
+        );
+    }
+
+    if let Some(run) = longest_run(samples, |status| {
+        matches!(status, ChargingStatus::Charging(_))
+    }) {
+        let points = spread(run, 0.0, 100.0);
+
+        // The voltage peaks where charging switches from the ascending to the descending phase
+        let peak = run
+            .iter()
+            .enumerate()
+            .max_by_key(|&(_, sample)| sample.voltage)
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        let (ascending, descending) = points.split_at(peak + 1);
+
+        for &(phase, points) in &[(false, ascending), (true, descending)] {
+            if points.len() >= MIN_SAMPLES {
+                curves.extend(
+                    VoltageCurve::new(points)
+                        .ok()
+                        .map(|curve| (ChargingStatus::Charging(phase), curve)),
+                );
+            }
+        }
+    }
+
+    curves
+}
+
+/// Write curves into a directory in the layout `battery::load_curves` reads, returning the
+/// files written
+pub fn write_curves(
+    dir: &Path,
+    curves: &[(ChargingStatus, VoltageCurve)],
+) -> Result<Vec<PathBuf>, Error> {
+    fs::create_dir_all(dir)?;
+
+    let mut written = Vec::new();
+    for &(charging_status, ref curve) in curves {
+        let name = curve_file_name(charging_status)
+            .ok_or_else(|| format_err!("No curve file for status: {:?}", charging_status))?;
+        let path = dir.join(name);
+        fs::write(&path, curve.to_string())?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Find the longest run of consecutive samples with a matching status and no gaps
+fn longest_run<F: Fn(ChargingStatus) -> bool>(samples: &[Sample], wanted: F) -> Option<&[Sample]> {
+    let mut longest: Option<&[Sample]> = None;
+    let mut start = 0;
+
+    for end in 1..=samples.len() {
+        let split = end == samples.len()
+            || !wanted(samples[end].charging_status)
+            || !wanted(samples[start].charging_status)
+            || samples[end].time.saturating_sub(samples[end - 1].time) > MAX_GAP;
+        if split {
+            let run = &samples[start..end];
+            if wanted(run[0].charging_status)
+                && run.len() >= MIN_SAMPLES
+                && longest.map_or(true, |longest| run.len() > longest.len())
+            {
+                longest = Some(run);
+            }
+            start = end;
+        }
+    }
+
+    longest
+}
+
+/// Give each sample in a run a charge going steadily from `first` to `last` over time
+fn spread(run: &[Sample], first: f32, last: f32) -> Vec<(u16, f32)> {
+    let start = run[0].time;
+    let length = (run[run.len() - 1].time - start).max(1) as f32;
+    run.iter()
+        .filter_map(|sample| {
+            let t = (sample.time - start) as f32 / length;
+            sample
+                .voltage
+                .map(|voltage| (voltage, first + (last - first) * t))
+        })
+        .collect()
+}
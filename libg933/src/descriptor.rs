@@ -0,0 +1,188 @@
+//! Reading HID report descriptors, to tell which of a device's interfaces speaks HID++
+
+/// ID of HID++ short reports
+pub const REPORT_SHORT: u8 = 0x10;
+
+/// ID of HID++ long reports, which every request is sent as
+pub const REPORT_LONG: u8 = 0x11;
+
+/// A report declared in a report descriptor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Report {
+    /// Report ID
+    pub id: u8,
+    /// Usage page in effect where the report ID was declared
+    pub usage_page: u16,
+}
+
+impl Report {
+    /// Whether the report is on a vendor-defined usage page (0xff00-0xffff)
+    pub fn is_vendor(&self) -> bool {
+        self.usage_page >= 0xff00
+    }
+}
+
+/// Find the reports declared in a report descriptor. Parsing stops at the first truncated item
+pub fn reports(descriptor: &[u8]) -> Vec<Report> {
+    let mut reports = Vec::new();
+    let mut usage_page = 0u16;
+    // Usage pages saved by Push items
+    let mut stack = Vec::new();
+
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+
+        // Long items give their data size in the next byte, and none are defined anyway
+        if prefix == 0xfe {
+            let size = descriptor.get(i + 1).map_or(0, |&size| size as usize);
+            i += 3 + size;
+            continue;
+        }
+
+        let size = match prefix & 0x03 {
+            3 => 4,
+            size => size as usize,
+        };
+        let data = match descriptor.get(i + 1..i + 1 + size) {
+            Some(data) => data,
+            None => break,
+        };
+        // Item data is little endian
+        let value = data
+            .iter()
+            .rev()
+            .fold(0u32, |value, &byte| value << 8 | u32::from(byte));
+
+        match prefix & 0xfc {
+            // Usage Page
+            0x04 => usage_page = value as u16,
+            // Report ID
+            0x84 => reports.push(Report {
+                id: value as u8,
+                usage_page,
+            }),
+            // Push
+            0xa4 => stack.push(usage_page),
+            // Pop
+            0xb4 => usage_page = stack.pop().unwrap_or(usage_page),
+            _ => (),
+        }
+
+        i += 1 + size;
+    }
+
+    reports
+}
+
+/// Whether a report descriptor declares the vendor-defined HID++ long report
+pub fn is_hidpp(descriptor: &[u8]) -> bool {
+    reports(descriptor)
+        .iter()
+        .any(|report| report.id == REPORT_LONG && report.is_vendor())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// HID++ interface of the G933 receiver: only the long report, on vendor page 0xff43
+    const G933_HIDPP: [u8; 28] = [
+        0x06, 0x43, 0xff, // Usage Page (0xff43)
+        0x0a, 0x02, 0x06, // Usage (0x0602)
+        0xa1, 0x01, // Collection (Application)
+        0x85, 0x11, // Report ID (0x11)
+        0x75, 0x08, // Report Size (8)
+        0x95, 0x13, // Report Count (19)
+        0x15, 0x00, // Logical Minimum (0)
+        0x26, 0xff, 0x00, // Logical Maximum (255)
+        0x09, 0x02, // Usage (0x02)
+        0x81, 0x00, // Input
+        0x09, 0x02, // Usage (0x02)
+        0x91, 0x00, // Output
+        0xc0, // End Collection
+    ];
+
+    /// Media keys interface, like the one next to the HID++ interface
+    const CONSUMER_CONTROL: [u8; 27] = [
+        0x05, 0x0c, // Usage Page (Consumer)
+        0x09, 0x01, // Usage (Consumer Control)
+        0xa1, 0x01, // Collection (Application)
+        0x85, 0x01, // Report ID (0x01)
+        0x15, 0x00, // Logical Minimum (0)
+        0x25, 0x01, // Logical Maximum (1)
+        0x75, 0x01, // Report Size (1)
+        0x95, 0x04, // Report Count (4)
+        0x09, 0xe9, // Usage (Volume Up)
+        0x09, 0xea, // Usage (Volume Down)
+        0x09, 0xe2, // Usage (Mute)
+        0x09, 0xcd, // Usage (Play/Pause)
+        0x81, 0x02, // Input
+        0xc0, // End Collection
+    ];
+
+    #[test]
+    fn finds_hidpp_interface() {
+        assert_eq!(
+            reports(&G933_HIDPP),
+            vec![Report {
+                id: REPORT_LONG,
+                usage_page: 0xff43,
+            }]
+        );
+        assert!(is_hidpp(&G933_HIDPP));
+    }
+
+    #[test]
+    fn skips_consumer_control() {
+        assert_eq!(
+            reports(&CONSUMER_CONTROL),
+            vec![Report {
+                id: 0x01,
+                usage_page: 0x0c,
+            }]
+        );
+        assert!(!is_hidpp(&CONSUMER_CONTROL));
+    }
+
+    #[test]
+    fn needs_vendor_page_for_hidpp() {
+        // Report 0x11 on the consumer page is just another report
+        let mut descriptor = CONSUMER_CONTROL.to_vec();
+        descriptor[7] = REPORT_LONG;
+        assert!(!is_hidpp(&descriptor));
+    }
+
+    #[test]
+    fn restores_usage_page_on_pop() {
+        let descriptor = [
+            0x06, 0x00, 0xff, // Usage Page (0xff00)
+            0xa4, // Push
+            0x05, 0x0c, // Usage Page (Consumer)
+            0x85, 0x03, // Report ID (0x03)
+            0xb4, // Pop
+            0x85, 0x11, // Report ID (0x11)
+        ];
+        assert_eq!(
+            reports(&descriptor),
+            vec![
+                Report {
+                    id: 0x03,
+                    usage_page: 0x0c,
+                },
+                Report {
+                    id: REPORT_LONG,
+                    usage_page: 0xff00,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_truncated_item() {
+        // The Report ID item is missing its data
+        let descriptor = [0x06, 0x00, 0xff, 0x85];
+        assert!(reports(&descriptor).is_empty());
+        assert!(!is_hidpp(&G933_HIDPP[..9]));
+    }
+}
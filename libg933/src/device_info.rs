@@ -11,6 +11,13 @@ pub struct DeviceInfo {
     model_id: [u8; 6],
 }
 
+impl DeviceInfo {
+    /// Serial number, as the unit ID in hex
+    pub fn serial(&self) -> String {
+        self.unit_id.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
 impl FromBytes for DeviceInfo {
     fn from_bytes(bytes: &[u8]) -> Self {
         Self {
@@ -0,0 +1,130 @@
+//! Estimate battery wear from a recorded history of discharge cycles
+//!
+//! As a battery ages it holds less charge, so it runs flat sooner, and its internal resistance
+//! goes up, so its voltage drops further when the charger is unplugged and it starts powering the
+//! headset. Both are measured for every discharge in the history and compared to the first one.
+
+use std::time::Duration;
+
+use battery::{ChargingStatus, Sample};
+
+/// Gap between samples (in seconds) that is taken to mean the headset was off in between, which
+/// ends a discharge
+const MAX_GAP: u64 = 15 * 60;
+
+/// Least charge (in percentage points) a discharge has to use up to count as a cycle, anything
+/// shorter is too noisy to extrapolate from
+const MIN_DEPTH: f32 = 50.0;
+
+/// Time after unplugging (in seconds) over which the loaded voltage is measured
+const SAG_WINDOW: u64 = 5 * 60;
+
+/// A single discharge found in the history
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cycle {
+    /// Seconds since the Unix epoch at the start of the discharge
+    pub start: u64,
+    /// Seconds since the Unix epoch at the end of the discharge
+    pub end: u64,
+    /// Charge percentage used up over the discharge
+    pub depth: f32,
+    /// Time a discharge from full to empty would take at the same rate
+    pub runtime: Duration,
+    /// Voltage drop (in mV) over the first minutes after the charger was unplugged, if the
+    /// history covers it
+    pub sag: Option<u16>,
+}
+
+/// Battery health compared to the first recorded cycle
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    /// Runtime of the latest cycle as a percentage of the first one's, capped at 100
+    pub percentage: f32,
+    /// First cycle found in the history
+    pub first: Cycle,
+    /// Latest cycle found in the history
+    pub latest: Cycle,
+    /// Number of cycles found in the history
+    pub cycles: usize,
+}
+
+/// Find the discharge cycles in a history of samples, in the order they were taken
+pub fn cycles(samples: &[Sample]) -> Vec<Cycle> {
+    let mut cycles = Vec::new();
+    let mut start = 0;
+
+    for end in 1..=samples.len() {
+        let split = end == samples.len()
+            || samples[end].charging_status != samples[start].charging_status
+            || samples[end].time.saturating_sub(samples[end - 1].time) > MAX_GAP;
+        if !split {
+            continue;
+        }
+
+        if samples[start].charging_status == ChargingStatus::Discharging {
+            // Sag can only be measured if the history catches the charger being unplugged
+            let unplugged =
+                start > 0 && samples[start].time.saturating_sub(samples[start - 1].time) <= MAX_GAP;
+            cycles.extend(cycle(unplugged, &samples[start..end]));
+        }
+        start = end;
+    }
+
+    cycles
+}
+
+/// Estimate battery health from a history of samples, or `None` if it has no full enough
+/// discharge cycles
+pub fn estimate(samples: &[Sample]) -> Option<Health> {
+    let cycles = cycles(samples);
+    let first = *cycles.first()?;
+    let latest = *cycles.last()?;
+
+    Some(Health {
+        percentage: (latest.runtime.as_secs_f32() / first.runtime.as_secs_f32() * 100.0).min(100.0),
+        first,
+        latest,
+        cycles: cycles.len(),
+    })
+}
+
+/// Measure a single discharge, if it is deep enough to count as a cycle
+fn cycle(unplugged: bool, run: &[Sample]) -> Option<Cycle> {
+    let charged = run
+        .iter()
+        .filter_map(|sample| sample.charge.map(|charge| (sample.time, charge)))
+        .collect::<Vec<_>>();
+    let &(start, first_charge) = charged.first()?;
+    let &(end, last_charge) = charged.last()?;
+
+    let depth = first_charge - last_charge;
+    if depth < MIN_DEPTH || end <= start {
+        return None;
+    }
+
+    // The charger holds the voltage up while it is plugged in, so the baseline is the first
+    // sample on the battery, before the load has pulled it down
+    let unloaded = run[0].voltage.filter(|_| unplugged);
+    let sag = unloaded.and_then(|unloaded| {
+        let loaded = run[1..]
+            .iter()
+            .take_while(|sample| sample.time - run[0].time <= SAG_WINDOW)
+            .filter_map(|sample| sample.voltage.map(u32::from))
+            .collect::<Vec<_>>();
+        if loaded.is_empty() {
+            return None;
+        }
+        let loaded = loaded.iter().sum::<u32>() / loaded.len() as u32;
+        u32::from(unloaded)
+            .checked_sub(loaded)
+            .map(|sag| sag as u16)
+    });
+
+    Some(Cycle {
+        start,
+        end,
+        depth,
+        runtime: Duration::from_secs(((end - start) as f32 * 100.0 / depth).round() as u64),
+        sag,
+    })
+}
@@ -0,0 +1,171 @@
+//! Rolling history of battery readings, used to estimate how long the battery will last
+
+use failure::Error;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use battery::{read_samples, ChargePhase, ChargingStatus, Sample};
+use xdg;
+
+/// How far back (in seconds) readings are used for estimates
+const WINDOW: u64 = 2 * 60 * 60;
+
+/// Shortest time (in seconds) between readings kept in the history, so readings taken in quick
+/// succession don't crowd out older ones
+const MIN_SPACING: u64 = 60;
+
+/// Shortest time (in seconds) the history has to cover before estimating anything
+const MIN_SPAN: u64 = 10 * 60;
+
+/// Fewest readings needed to estimate anything
+const MIN_SAMPLES: usize = 3;
+
+/// How long the battery has left until it is empty or full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuntimeEstimate {
+    /// Charging status the estimate was made for
+    pub charging_status: ChargingStatus,
+    /// Change in charge, in percentage points per hour. Negative while discharging
+    pub rate: f32,
+    /// Time until the battery is empty while discharging, or full while charging
+    pub remaining: Duration,
+}
+
+/// Recent battery readings of a device
+#[derive(Debug, Clone, Default)]
+pub struct BatteryHistory {
+    samples: VecDeque<Sample>,
+}
+
+impl BatteryHistory {
+    /// Create an empty history
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// File that the history of the device with the given serial number is kept in between
+    /// runs, `$XDG_CACHE_HOME/g933-utils/history/<serial>.csv`
+    pub fn default_path(serial: &str) -> Option<PathBuf> {
+        xdg::cache_dir().map(|dir| dir.join("history").join(format!("{}.csv", serial)))
+    }
+
+    /// Load a history saved with `save`, or an empty one if the file doesn't exist
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(error) => bail!("{}: {}", path.display(), error),
+        };
+        let samples = read_samples(BufReader::new(file))
+            .map_err(|error| format_err!("{}: {}", path.display(), error))?;
+
+        let mut history = Self::new();
+        for sample in samples {
+            history.push(sample);
+        }
+        Ok(history)
+    }
+
+    /// Save the history to a file, creating its directory if needed
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = self
+            .samples
+            .iter()
+            .map(|sample| format!("{}\n", sample))
+            .collect::<String>();
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Add a reading, dropping readings that are too old or from before the charger was plugged
+    /// in or out
+    pub fn push(&mut self, sample: Sample) {
+        if let Some(last) = self.samples.back().cloned() {
+            let plugged = sample.charging_status.charger_connected();
+            if sample.time < last.time || plugged != last.charging_status.charger_connected() {
+                self.samples.clear();
+            } else if sample.time - last.time < MIN_SPACING {
+                return;
+            }
+        }
+
+        while self
+            .samples
+            .front()
+            .is_some_and(|first| sample.time - first.time > WINDOW)
+        {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(sample);
+    }
+
+    /// Charging phase as of the latest reading, for a new reading to carry on from with
+    /// `Device::set_charge_phase`. Starts over if the latest reading is too old to be from the
+    /// same charge
+    pub fn charge_phase(&self) -> ChargePhase {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        match self.samples.back() {
+            Some(last) if now.saturating_sub(last.time) <= WINDOW => {
+                ChargePhase::resume(&self.samples)
+            }
+            _ => ChargePhase::default(),
+        }
+    }
+
+    /// Readings in the history, oldest first
+    pub fn samples(&self) -> &VecDeque<Sample> {
+        &self.samples
+    }
+
+    /// Estimate how long is left until the battery is empty or full, from how quickly the
+    /// charge has been changing. Returns `None` without enough readings with a known charge, or
+    /// when the charge isn't going the way the charging status says it should
+    pub fn estimate(&self) -> Option<RuntimeEstimate> {
+        let last = *self.samples.back()?;
+        let charge = last.charge?;
+
+        let points = self
+            .samples
+            .iter()
+            .filter_map(|sample| sample.charge.map(|charge| (sample.time, charge)))
+            .collect::<Vec<_>>();
+        if points.len() < MIN_SAMPLES || points[points.len() - 1].0 - points[0].0 < MIN_SPAN {
+            return None;
+        }
+
+        // Least squares fit of charge over time
+        let start = points[0].0;
+        let n = points.len() as f64;
+        let mean_t = points.iter().map(|&(t, _)| (t - start) as f64).sum::<f64>() / n;
+        let mean_c = points.iter().map(|&(_, c)| f64::from(c)).sum::<f64>() / n;
+        let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), &(t, c)| {
+            let dt = (t - start) as f64 - mean_t;
+            (cov + dt * (f64::from(c) - mean_c), var + dt * dt)
+        });
+        let rate = covariance / variance * 3600.0;
+
+        let hours = match last.charging_status {
+            ChargingStatus::Discharging if rate < 0.0 => f64::from(charge) / -rate,
+            ChargingStatus::Charging(_) | ChargingStatus::SlowCharging if rate > 0.0 => {
+                (100.0 - f64::from(charge)) / rate
+            }
+            _ => return None,
+        };
+
+        Some(RuntimeEstimate {
+            charging_status: last.charging_status,
+            rate: rate as f32,
+            remaining: Duration::from_secs((hours * 3600.0).round() as u64),
+        })
+    }
+}
@@ -0,0 +1,88 @@
+//! Show the battery level on the headset lights
+
+use failure::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use battery::{BatteryStatus, ChargingStatus};
+use lights::{Color, Config, Effect, Light, ProfileType};
+use Device;
+
+/// Breathing period used to show that the battery is charging
+const CHARGING_PERIOD: Duration = Duration::from_secs(4);
+
+/// Color for a charge percentage, going from red when empty through yellow to green when full
+pub fn charge_color(charge: f32) -> Color {
+    let charge = charge.clamp(0.0, 100.0) / 100.0;
+    if charge < 0.5 {
+        Color::new(255, (charge * 2.0 * 255.0).round() as u8, 0)
+    } else {
+        Color::new(((1.0 - charge) * 2.0 * 255.0).round() as u8, 255, 0)
+    }
+}
+
+/// Side light effect showing a battery status, which breathes while charging. The lights are
+/// white if the charge isn't known
+pub fn battery_effect(status: &BatteryStatus) -> Effect {
+    let color = status
+        .charge
+        .map_or(Color::new(255, 255, 255), charge_color);
+    match status.charging_status {
+        ChargingStatus::Charging(_) | ChargingStatus::SlowCharging => Effect::Breathing {
+            color,
+            period: CHARGING_PERIOD,
+            brightness: 100,
+        },
+        _ => Effect::Static { color },
+    }
+}
+
+/// Show the battery level on the side lights, or the only light of models without them,
+/// checking it every `interval` until `running` is cleared. Then put back whatever the light was
+/// showing before and give the device back control of its lights
+pub fn run(device: &mut Device, interval: Duration, running: &AtomicBool) -> Result<(), Error> {
+    let lights = &device.model().lights;
+    let light = if lights.contains(&Light::Side) {
+        Light::Side
+    } else {
+        *lights
+            .first()
+            .ok_or_else(|| format_err!("{} has no lights", device.model().name))?
+    };
+
+    let previous = device.get_lights(light)?;
+    device.set_lighting_host_control(true)?;
+    let result = show(device, light, interval, running);
+    let restored = device.set_lights(&previous);
+    let released = device.set_lighting_host_control(false);
+    result.and(restored.map(|_| ())).and(released)
+}
+
+fn show(
+    device: &mut Device,
+    light: Light,
+    interval: Duration,
+    running: &AtomicBool,
+) -> Result<(), Error> {
+    let mut last_update: Option<Instant> = None;
+
+    while running.load(Ordering::SeqCst) {
+        if last_update.map_or(true, |time| time.elapsed() >= interval) {
+            let status = device.get_battery_status()?;
+            debug!("Showing battery status: {:?}", status);
+            let effect = device.correct_effect(battery_effect(&status));
+            device.set_lights(&Config {
+                light,
+                effect,
+                profile_type: ProfileType::Temporary,
+            })?;
+            last_update = Some(Instant::now());
+        }
+
+        // Wake up regularly so we notice when we're told to stop
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Ok(())
+}
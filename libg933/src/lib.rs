@@ -6,33 +6,59 @@
 #![cfg_attr(feature = "cargo-clippy", allow(useless_format))]
 
 extern crate byteorder;
+#[cfg(feature = "dbus")]
+extern crate dbus;
+#[cfg(feature = "dbus")]
+extern crate dbus_crossroads;
 #[macro_use]
 extern crate failure;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
 extern crate udev;
 
 #[macro_use]
 mod macros;
 mod future;
+pub mod animation;
+pub mod audio;
 pub mod battery;
 pub mod buttons;
+pub mod calibration;
+pub mod descriptor;
 pub mod device_info;
+pub mod health;
+pub mod history;
+pub mod indicator;
 pub mod lights;
+pub mod logfile;
+#[cfg(feature = "dbus")]
+pub mod notifications;
+pub mod registry;
+#[cfg(all(test, feature = "dbus"))]
+mod test_bus;
+#[cfg(feature = "dbus")]
+pub mod upower;
+pub mod xdg;
 
 use byteorder::{BigEndian, ByteOrder};
 use failure::Error;
 use future::Future;
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::mpsc::{self, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::clone::Clone;
 
 type RequestsMap = HashMap<[u8; 4], Sender<[u8; 20]>>;
@@ -42,10 +68,14 @@ type FeatureMap = HashMap<u16, Future<Option<Feature>>>;
 pub const FEATURE_ROOT: u16 = 0x0000;
 /// Battery levels and charging status
 pub const FEATURE_BATTERY: u16 = 0x1f20;
+/// Battery voltage and charging status, on other HID++ devices
+pub const FEATURE_BATTERY_VOLTAGE: u16 = 0x1001;
+/// Battery level and charging status, on newer HID++ devices
+pub const FEATURE_UNIFIED_BATTERY: u16 = 0x1004;
 /// Device feature set
 pub const FEATURE_SET: u16 = 0x0001;
 /// Device and firmware info
-pub const FEATURE_DEVINFO: u16 = 0x0002;
+pub const FEATURE_DEVINFO: u16 = 0x0003;
 /// Device name
 pub const FEATURE_DEVNAME: u16 = 0x0005;
 /// Buttons
@@ -69,6 +99,16 @@ pub trait FromBytes {
     fn from_bytes(bytes: &[u8]) -> Self;
 }
 
+/// Convert a series of bytes from the device to a struct that implements this trait, failing if
+/// they don't describe one
+pub trait TryFromBytes
+where
+    Self: Sized,
+{
+    /// Convert a series of bytes to a struct that implements this trait
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error>;
+}
+
 /// Convert a series of bytes to a struct that implements this trait
 pub trait FromBytesWithDevice
 where
@@ -84,6 +124,17 @@ pub struct Device {
     file: File,
     requests: Arc<Mutex<RequestsMap>>,
     features: Arc<Mutex<FeatureMap>>,
+    /// Why reading from the device stopped, if it has
+    read_error: Arc<Mutex<Option<(io::ErrorKind, String)>>>,
+    /// Feature the battery is read through, once we know which one the device has
+    battery_feature: Option<battery::BatteryFeature>,
+    charge_phase: battery::ChargePhase,
+    /// Whether the charger was plugged in last we heard, if we know
+    charger_connected: Option<bool>,
+    /// Whether we have asked the headset about the charger yet
+    charger_primed: bool,
+    /// Serial number, once it has been read
+    serial: Option<String>,
 }
 
 #[derive(Clone)]
@@ -98,11 +149,25 @@ pub struct Feature {
 impl Device {
     /// Construct a new `Device` from a `HidDevice`
     pub fn new(path: &Path, dev_match: StaticDeviceMatch) -> Result<Self, Error> {
+        Self::from_file(
+            OpenOptions::new().read(true).write(true).open(path)?,
+            dev_match,
+        )
+    }
+
+    /// Talk to a device through an open file, which takes one report per read and write
+    fn from_file(file: File, dev_match: StaticDeviceMatch) -> Result<Self, Error> {
         let device = Self {
             dev_match: dev_match,
-            file: OpenOptions::new().read(true).write(true).open(path)?,
+            file,
             requests: Arc::new(Mutex::new(HashMap::new())),
             features: Arc::new(Mutex::new(HashMap::new())),
+            read_error: Arc::new(Mutex::new(None)),
+            battery_feature: None,
+            charge_phase: battery::ChargePhase::default(),
+            charger_connected: None,
+            charger_primed: false,
+            serial: None,
         };
 
         let mut future_root_feature = Future::new();
@@ -118,21 +183,30 @@ impl Device {
 
         let mut file = device.file.try_clone()?;
         let requests = Arc::clone(&device.requests);
+        let read_error = Arc::clone(&device.read_error);
         thread::spawn(move || {
             use std::io::Read;
 
             let mut data = [0u8; 20];
 
             loop {
-                thread::sleep(Duration::from_millis(100));
+                // Reading blocks until the device sends something, so we must not hold the lock
+                // while doing it or nobody else could add requests in the meantime
+                match file.read(&mut data) {
+                    Ok(0) => continue,
+                    Ok(_) => (),
+                    Err(error) => {
+                        // Most likely the receiver was unplugged. Dropping the senders lets
+                        // everyone waiting for a response know right away
+                        error!("Could not read from device: {}", error);
+                        *read_error.lock().unwrap() = Some((error.kind(), error.to_string()));
+                        requests.lock().unwrap().clear();
+                        return;
+                    }
+                }
 
                 let mut requests = requests.lock().unwrap();
 
-                // If there are no requests or it times out without reading anything, loop again
-                if requests.is_empty() || file.read(&mut data).unwrap() == 0 {
-                    continue;
-                }
-
                 if let Some(sender) = requests.remove(&data[..4]) {
                     debug!(
                         "Got data from device: {}",
@@ -141,7 +215,8 @@ impl Device {
                             .collect::<Vec<String>>()
                             .join(" ")
                     );
-                    sender.send(data).unwrap();
+                    // The receiver may have given up waiting already, which is fine
+                    sender.send(data).ok();
                 }
             }
         });
@@ -149,6 +224,15 @@ impl Device {
         Ok(device)
     }
 
+    /// Fail with the error that stopped reading from the device, if it has stopped. Nothing
+    /// would ever answer a request after that
+    fn check_reader(&self) -> Result<(), io::Error> {
+        match *self.read_error.lock().unwrap() {
+            Some((kind, ref message)) => Err(io::Error::new(kind, message.clone())),
+            None => Ok(()),
+        }
+    }
+
     /// Send a raw request to the device
     pub fn raw_request(&mut self, request: &[u8]) -> Result<[u8; 20], Error> {
         use std::io::Write;
@@ -173,6 +257,7 @@ impl Device {
             }
             thread::sleep(Duration::from_millis(100));
         }
+        self.check_reader()?;
 
         // Try 3 times then fail if it doesn't return anything
         for _ in 0..3 {
@@ -187,10 +272,16 @@ impl Device {
             match receiver.recv_timeout(Duration::from_secs(2)) {
                 Ok(response) => return Ok(response),
                 Err(mpsc::RecvTimeoutError::Timeout) => (),
-                Err(error) => return Err(error.into()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    self.check_reader()?;
+                    bail!("Stopped reading from the device");
+                }
             }
         }
 
+        // Stop waiting for a response, or this request could never be made again
+        self.requests.lock().unwrap().remove(&data[..4]);
+
         bail!("Request timed out");
     }
 
@@ -210,8 +301,23 @@ impl Device {
     fn resolve_feature(&mut self, feature: u16) -> Result<Feature, Error> {
         let (must_resolve, mut future_feature) = self.find_future_feature(feature);
         if must_resolve {
-            let (index, _, _) = self.get_feature(feature)?;
+            let (index, _, _) = match self.get_feature(feature) {
+                Ok(info) => info,
+                Err(error) => {
+                    // Forget about the feature so the next request tries to resolve it again
+                    self.features.lock().unwrap().remove(&feature);
+                    future_feature.set(None);
+                    return Err(error);
+                }
+            };
             if index == 0 {
+                if self.model().features.contains(&feature) {
+                    warn!(
+                        "{} should support feature {:#06x}, but doesn't",
+                        self.model().name,
+                        feature
+                    );
+                }
                 future_feature.set(None)
             } else {
                 future_feature.set(Some(Feature {
@@ -269,6 +375,37 @@ impl Device {
             .map(|response| device_info::DeviceInfo::from_bytes(&response[4..]))
     }
 
+    /// Serial number of the device, which tells headsets of the same model apart. It is only
+    /// read from the device the first time
+    pub fn serial(&mut self) -> Result<String, Error> {
+        if let Some(ref serial) = self.serial {
+            return Ok(serial.clone());
+        }
+        let serial = self.get_device_info()?.serial();
+        self.serial = Some(serial.clone());
+        Ok(serial)
+    }
+
+    /// USB product ID of the device
+    pub fn product_id(&self) -> u16 {
+        self.dev_match.pid
+    }
+
+    /// Description of the device's model
+    pub fn model(&self) -> &'static registry::DeviceModel {
+        self.dev_match.model
+    }
+
+    /// How the headset is connected
+    pub fn connection(&self) -> registry::Connection {
+        self.dev_match.connection
+    }
+
+    /// Whether the headset is running on its battery, which it doesn't report while wired
+    pub fn has_battery(&self) -> bool {
+        self.dev_match.connection == registry::Connection::Wireless
+    }
+
     /// Get device name
     pub fn get_device_name(&mut self) -> Result<String, Error> {
         let length = self.feature_request(FEATURE_DEVNAME, &[0x01])?[4];
@@ -292,9 +429,133 @@ impl Device {
     /// Set light configuration
     pub fn set_lights(&mut self, lights: &lights::Config) -> Result<lights::Config, Error> {
         let request = v![0x31, @lights.as_bytes()];
-        Ok(lights::Config::from_bytes(
-            &self.feature_request(FEATURE_LIGHTS, &request)?[4..],
-        ))
+        lights::Config::try_from_bytes(&self.feature_request(FEATURE_LIGHTS, &request)?[4..])
+    }
+
+    /// Adjust a color for this device's LEDs, so it looks the way it would on a screen
+    pub fn correct_color(&self, color: lights::Color) -> lights::Color {
+        color.corrected(&self.dev_match.model.correction())
+    }
+
+    /// Adjust the color of an effect for this device's LEDs
+    pub fn correct_effect(&self, effect: lights::Effect) -> lights::Effect {
+        effect.corrected(&self.dev_match.model.correction())
+    }
+
+    /// Get the current configuration of a light
+    pub fn get_lights(&mut self, light: lights::Light) -> Result<lights::Config, Error> {
+        self.read_lights(light, 0x00)
+    }
+
+    /// Get the configuration of a light stored onboard as the power-on default
+    pub fn get_stored_lights(&mut self, light: lights::Light) -> Result<lights::Config, Error> {
+        let mut config = self.read_lights(light, 0x01)?;
+        config.profile_type = lights::ProfileType::Permanent;
+        Ok(config)
+    }
+
+    fn read_lights(&mut self, light: lights::Light, profile: u8) -> Result<lights::Config, Error> {
+        let light = match light {
+            lights::Light::Logo => 0x00,
+            lights::Light::Side => 0x01,
+        };
+        let request = [0xe1, light, profile];
+        lights::Config::try_from_bytes(&self.feature_request(FEATURE_LIGHTS, &request)?[4..])
+    }
+
+    /// Set the effect of a light now, as the power-on default, or both
+    pub fn store_lights(
+        &mut self,
+        light: lights::Light,
+        effect: lights::Effect,
+        target: lights::Target,
+    ) -> Result<(), Error> {
+        for profile_type in target.profile_types() {
+            self.set_lights(&lights::Config {
+                light,
+                effect,
+                profile_type: *profile_type,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Fade a light from one configuration to another over some time. Static colors, breathing
+    /// colors and brightness are blended smoothly, anything else just switches at the end.
+    /// Colors are sent as they are, so correct them first if needed
+    pub fn transition_lights(
+        &mut self,
+        from: &lights::Config,
+        to: &lights::Config,
+        duration: Duration,
+        easing: animation::Easing,
+    ) -> Result<(), Error> {
+        ensure!(
+            from.light == to.light,
+            "Can only transition between configurations of the same light"
+        );
+
+        let colors = (apparent_color(&from.effect), apparent_color(&to.effect));
+        if let (Some(start), Some(end)) = colors {
+            animation::play_transition(
+                self,
+                &mut animation::Transition {
+                    light: to.light,
+                    from: start,
+                    to: end,
+                    duration,
+                    easing,
+                },
+            )?;
+        }
+
+        self.set_lights(to)?;
+        Ok(())
+    }
+
+    /// Blink some lights in a color a number of times, then put back the effects they were
+    /// showing before. The color is sent as it is, so correct it first if needed
+    pub fn flash_lights(
+        &mut self,
+        lights: &[lights::Light],
+        color: lights::Color,
+        times: u32,
+        period: Duration,
+    ) -> Result<(), Error> {
+        let previous = lights
+            .iter()
+            .map(|light| self.get_lights(*light))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let result = self.blink(lights, color, times, period);
+
+        let mut restored = Ok(());
+        for config in &previous {
+            restored = restored.and(self.set_lights(config).map(|_| ()));
+        }
+        result.and(restored)
+    }
+
+    fn blink(
+        &mut self,
+        lights: &[lights::Light],
+        color: lights::Color,
+        times: u32,
+        period: Duration,
+    ) -> Result<(), Error> {
+        for _ in 0..times {
+            for effect in &[lights::Effect::Static { color }, lights::Effect::Off] {
+                for light in lights {
+                    self.set_lights(&lights::Config {
+                        light: *light,
+                        effect: *effect,
+                        profile_type: lights::ProfileType::Temporary,
+                    })?;
+                }
+                thread::sleep(period / 2);
+            }
+        }
+        Ok(())
     }
 
     /// Get startup effect enabled status
@@ -322,6 +583,24 @@ impl Device {
         }
     }
 
+    /// Take or give back control of the lights. While we have control the onboard effects are
+    /// paused, and they resume once control is given back
+    pub fn set_lighting_host_control(&mut self, enable: bool) -> Result<(), Error> {
+        let request = [0x81, enable as u8, enable as u8];
+        match self.feature_request(FEATURE_LIGHTS, &request) {
+            Ok(response) => {
+                ensure!(
+                    response[4..6] == request[1..3],
+                    "set_lighting_host_control response did not match the request: expected {:?}, was {:?}",
+                    &request[1..3],
+                    &response[4..6],
+                );
+                Ok(())
+            }
+            Err(error) => Err(error),
+        }
+    }
+
     /// Get number of buttons on device
     pub fn get_button_count(&mut self) -> Result<u8, Error> {
         self.feature_request(FEATURE_GKEY, &[0x01])
@@ -351,48 +630,56 @@ impl Device {
         }
     }
 
-    /// Get frequency of equalizer bands
-    pub fn get_equalizer_bands(&mut self) -> Result<[u16; 10], Error> {
-        let mut bands = [0; 10];
+    /// Get the equalizer layout of the model
+    fn equalizer_layout(&self) -> Result<registry::EqualizerLayout, Error> {
+        self.model()
+            .equalizer
+            .ok_or_else(|| format_err!("{} has no equalizer", self.model().name))
+    }
 
-        let request = [0x11, 0x00];
-        BigEndian::read_u16_into(
-            &self.feature_request(FEATURE_EQ, &request)?[5..19],
-            &mut bands[0..7],
-        );
+    /// Get frequency of equalizer bands
+    pub fn get_equalizer_bands(&mut self) -> Result<Vec<u16>, Error> {
+        let count = self.equalizer_layout()?.bands;
+        let mut bands = vec![0; count];
 
-        let request = [0x11, 0x07];
-        BigEndian::read_u16_into(
-            &self.feature_request(FEATURE_EQ, &request)?[5..11],
-            &mut bands[7..10],
-        );
+        // Each response holds up to 7 bands, starting from the one asked for
+        for start in (0..count).step_by(7) {
+            let end = (start + 7).min(count);
+            let request = [0x11, start as u8];
+            let response = self.feature_request(FEATURE_EQ, &request)?;
+            BigEndian::read_u16_into(&response[5..5 + (end - start) * 2], &mut bands[start..end]);
+        }
         Ok(bands)
     }
 
     /// Get equalizer
-    pub fn get_equalizer(&mut self) -> Result<[i8; 10], Error> {
+    pub fn get_equalizer(&mut self) -> Result<Vec<i8>, Error> {
+        let bands = self.equalizer_layout()?.bands;
         let request = [0x21];
         self.feature_request(FEATURE_EQ, &request)
-            .map(|response| unsafe {
-                let mut config = [0; 10];
-                config.copy_from_slice(&response[4..14]);
-                std::mem::transmute(config)
-            })
+            .map(|response| response[4..4 + bands].iter().map(|&b| b as i8).collect())
     }
 
-    /// Set equalizer
-    pub fn set_equalizer(&mut self, permanent: bool, config: [i8; 10]) -> Result<(), Error> {
+    /// Set equalizer, with a value for every band
+    pub fn set_equalizer(&mut self, permanent: bool, config: &[i8]) -> Result<(), Error> {
+        let bands = self.equalizer_layout()?.bands;
+        ensure!(
+            config.len() == bands,
+            "Expected {} equalizer values, got {}",
+            bands,
+            config.len()
+        );
         let permanent = if permanent { 0x02 } else { 0x00 };
-        let config = unsafe { std::mem::transmute::<_, [u8; 10]>(config) };
+        let config = config.iter().map(|&value| value as u8).collect::<Vec<_>>();
         // TODO: figure out what the 0x02 is
         let request = v![0x31, permanent, @config.iter()];
         match self.feature_request(FEATURE_EQ, &request) {
             Ok(response) => {
                 ensure!(
-                    response[5..15] == config,
+                    response[5..5 + bands] == config[..],
                     "set_equalizer response did not match the request: expected {:?}, was {:?}",
                     config,
-                    &response[5..15]
+                    &response[5..5 + bands]
                 );
                 Ok(())
             }
@@ -423,16 +710,89 @@ impl Device {
         }
     }
 
-    /// Get battery status and level
+    /// Get battery status and level. While charging, the voltage is followed across calls to
+    /// tell whether the battery is in the ascending or descending phase
     pub fn get_battery_status(&mut self) -> Result<battery::BatteryStatus, Error> {
-        battery::BatteryStatus::from_bytes(
+        let reading = self.get_battery_reading()?;
+        Ok(battery::BatteryStatus::from_reading(
             self.dev_match,
-            &self.feature_request(FEATURE_BATTERY, &[0x01])?[4..],
-        ).ok_or_else(|| format_err!("No charge/discharge curve defined for device"))
+            reading,
+        ))
+    }
+
+    /// Get the raw battery reading, from whichever battery feature the device has
+    pub fn get_battery_reading(&mut self) -> Result<battery::Reading, Error> {
+        let feature = self.battery_feature()?;
+        let response = self.feature_request(feature.id(), &[feature.status_function()])?;
+        Ok(self.track_charge_phase(feature.read_status(&response[4..])))
+    }
+
+    /// Find out which battery feature the device has, preferring the headset one, then unified
+    /// battery, then battery voltage
+    pub fn battery_feature(&mut self) -> Result<battery::BatteryFeature, Error> {
+        use battery::BatteryFeature;
+
+        ensure!(self.has_battery(), "No battery while the headset is wired");
+        if let Some(feature) = self.battery_feature {
+            return Ok(feature);
+        }
+
+        let feature = if self.get_feature(FEATURE_BATTERY)?.0 != 0 {
+            BatteryFeature::Headset
+        } else if self.get_feature(FEATURE_UNIFIED_BATTERY)?.0 != 0 {
+            let capabilities = self.feature_request(FEATURE_UNIFIED_BATTERY, &[0x01])?;
+            BatteryFeature::Unified {
+                state_of_charge: capabilities[5] & 0x02 != 0,
+            }
+        } else if self.get_feature(FEATURE_BATTERY_VOLTAGE)?.0 != 0 {
+            BatteryFeature::Voltage
+        } else {
+            bail!("No supported battery feature");
+        };
+
+        debug!("Battery feature: {:?}", feature);
+
+        self.battery_feature = Some(feature);
+        Ok(feature)
+    }
+
+    /// Fill in the charging phase of a reading, if it has a voltage to tell it by
+    fn track_charge_phase(&mut self, reading: battery::Reading) -> battery::Reading {
+        match reading.voltage {
+            Some(voltage) => battery::Reading {
+                charging_status: self.charge_phase.track(reading.charging_status, voltage),
+                ..reading
+            },
+            None => {
+                self.charge_phase = battery::ChargePhase::default();
+                reading
+            }
+        }
+    }
+
+    /// Carry on following the charging phase from earlier readings, such as the ones in
+    /// `BatteryHistory::charge_phase`. Without this, the descending phase can only be told apart
+    /// once a `Device` has seen the voltage peak itself
+    pub fn set_charge_phase(&mut self, charge_phase: battery::ChargePhase) {
+        self.charge_phase = charge_phase;
+    }
+
+    /// Take a timestamped battery sample, with the charge if there is a curve for the device
+    pub fn get_battery_sample(&mut self) -> Result<battery::Sample, Error> {
+        let status = self.get_battery_status()?;
+        Ok(battery::Sample::now(
+            status.charging_status,
+            status.voltage,
+            status.charge,
+        ))
     }
 
     /// Get poweroff timeout
     pub fn get_poweroff_timeout(&mut self) -> Result<Option<u8>, Error> {
+        ensure!(
+            self.has_battery(),
+            "No poweroff timeout while the headset is wired"
+        );
         match self.feature_request(FEATURE_BATTERY, &[0x11])?[4] {
             0 => Ok(None),
             t => Ok(Some(t)),
@@ -441,6 +801,10 @@ impl Device {
 
     /// Set poweroff timeout
     pub fn set_poweroff_timeout(&mut self, timeout: Option<u8>) -> Result<(), Error> {
+        ensure!(
+            self.has_battery(),
+            "No poweroff timeout while the headset is wired"
+        );
         let timeout = match timeout {
             None => 0,
             Some(t) => t,
@@ -460,58 +824,248 @@ impl Device {
         }
     }
 
+    /// Wait for an unsolicited notification from a feature, returning `None` if nothing arrives
+    /// before the timeout
+    fn next_notification(
+        &mut self,
+        feature: u16,
+        timeout: Duration,
+    ) -> Result<Option<[u8; 20]>, Error> {
+        let feature = self.resolve_feature(feature)?;
+        let header = [0x11, 0xff, feature.index, 0x00];
+        let (sender, receiver) = mpsc::channel();
+
+        self.requests.lock().unwrap().insert(header, sender);
+        self.check_reader()?;
+
+        match receiver.recv_timeout(timeout) {
+            Ok(response) => Ok(Some(response)),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.requests.lock().unwrap().remove(&header);
+                Ok(None)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                self.check_reader()?;
+                bail!("Stopped reading from the device");
+            }
+        }
+    }
+
     /// Watch for button presses/releases (g1, g2, g3)
     pub fn watch_buttons(&mut self, callback: fn(buttons::Buttons)) -> Result<(), Error> {
-        let feature = self.resolve_feature(FEATURE_GKEY)?;
-        let (sender, receiver) = mpsc::channel();
+        loop {
+            if let Some(response) = self.next_notification(FEATURE_GKEY, Duration::from_secs(2))? {
+                callback(buttons::Buttons::from_bytes(&response[4..]));
+            }
+        }
+    }
 
-        // Loop and keep adding the request to our pending request map
+    /// Wait for the next lighting sync notification, returning `None` if nothing arrives before
+    /// the timeout
+    pub fn wait_for_lighting_sync(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<lights::SyncEvent>, Error> {
+        match self.next_notification(FEATURE_LIGHTS, timeout)? {
+            Some(response) => Ok(Some(lights::SyncEvent::try_from_bytes(&response[4..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Watch for lighting sync notifications, sent when an effect starts a new cycle
+    pub fn watch_lighting_sync(&mut self, callback: fn(lights::SyncEvent)) -> Result<(), Error> {
         loop {
-            // Make sure we drop the lock before we try reading
-            {
-                let mut requests = self.requests.lock().unwrap();
+            if let Some(event) = self.wait_for_lighting_sync(Duration::from_secs(2))? {
+                callback(event);
+            }
+        }
+    }
+
+    /// Wait for the next battery notification, returning the events it stands for. Returns
+    /// nothing if the notification doesn't arrive before the timeout
+    pub fn wait_for_power_events(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<battery::PowerEvent>, Error> {
+        use battery::PowerEvent;
 
-                let header = [0x11, 0xff, feature.index, 0x00];
-                requests.insert(header, sender.clone());
+        // We can only tell the charger was plugged in or out if we knew its state before. Only ask
+        // once, after that notifications keep us up to date. Asking a headset that is off would
+        // block until the request times out, missing any notifications in the meantime
+        if !self.charger_primed {
+            self.charger_primed = true;
+            if let Ok(reading) = self.get_battery_reading() {
+                self.charger_connected = reading.charging_status.charger_connected();
             }
+        }
 
-            match receiver.recv_timeout(Duration::from_secs(2)) {
-                Ok(response) => callback(buttons::Buttons::from_bytes(&response[4..])),
-                Err(mpsc::RecvTimeoutError::Timeout) => (),
-                Err(error) => return Err(error.into()),
+        let feature = self.battery_feature()?;
+        let response = match self.next_notification(feature.id(), timeout)? {
+            Some(response) => response,
+            None => return Ok(Vec::new()),
+        };
+
+        let reading = match feature.read_notification(&response[4..]) {
+            Some(reading) => reading,
+            None => {
+                // The charger may change while the headset is off, so the next notification
+                // tells us where it is again
+                self.charger_connected = None;
+                self.charge_phase = battery::ChargePhase::default();
+                return Ok(vec![PowerEvent::PoweredOff]);
+            }
+        };
+
+        let mut events = Vec::new();
+        let connected = reading.charging_status.charger_connected();
+        match (self.charger_connected, connected) {
+            (Some(false), Some(true)) => events.push(PowerEvent::ChargerConnected),
+            (Some(true), Some(false)) => events.push(PowerEvent::ChargerDisconnected),
+            _ => (),
+        }
+        self.charger_connected = connected;
+
+        let reading = self.track_charge_phase(reading);
+        events.push(PowerEvent::StatusChanged(
+            battery::BatteryStatus::from_reading(self.dev_match, reading),
+        ));
+
+        Ok(events)
+    }
+
+    /// Watch for power events, sent when the charger is plugged in or out, the battery status
+    /// changes or the headset turns off
+    pub fn watch_power(&mut self, callback: fn(battery::PowerEvent)) -> Result<(), Error> {
+        loop {
+            for event in self.wait_for_power_events(Duration::from_secs(2))? {
+                callback(event);
             }
         }
     }
+
+    /// Restart the effect on a light of each follower, at the moment this device starts a new
+    /// cycle of its own effect on that light, so they all run in phase
+    pub fn sync_lights(
+        &mut self,
+        light: lights::Light,
+        followers: &mut [&mut Device],
+    ) -> Result<(), Error> {
+        let config = self.get_lights(light)?;
+        let period = match config.effect {
+            lights::Effect::Breathing { period, .. }
+            | lights::Effect::ColorCycle { period, .. } => period,
+            _ => bail!("Only breathing and color cycle effects can be synced"),
+        };
+
+        // Give up if we miss a whole cycle plus some slack
+        let deadline = Instant::now() + period + Duration::from_secs(1);
+        loop {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .ok_or_else(|| format_err!("Timed out waiting for lighting sync"))?;
+            match self.wait_for_lighting_sync(remaining)? {
+                Some(ref event) if event.light == light => break,
+                _ => (),
+            }
+        }
+
+        for follower in followers.iter_mut() {
+            follower.set_lights(&config)?;
+        }
+
+        Ok(())
+    }
 }
 
-/// Information about a supported device
-pub struct DeviceMatch<'a> {
-    pid: u16,
-    name: &'a str,
+/// The color a light effect shows on average, if it shows a single color
+fn apparent_color(effect: &lights::Effect) -> Option<lights::Color> {
+    let scale = |color: lights::Color, brightness: u8| {
+        let channel = |c: u8| (u16::from(c) * u16::from(brightness.min(100)) / 100) as u8;
+        lights::Color::new(
+            channel(color.red),
+            channel(color.green),
+            channel(color.blue),
+        )
+    };
+
+    match *effect {
+        lights::Effect::Off => Some(lights::Color::new(0, 0, 0)),
+        lights::Effect::Static { color } => Some(color),
+        lights::Effect::Breathing {
+            color, brightness, ..
+        } => Some(scale(color, brightness)),
+        lights::Effect::ColorCycle { .. } => None,
+    }
 }
 
-const SUPPORTED_DEVICES: &'static [DeviceMatch] = &[
-    DeviceMatch {
-        pid: 0x0a5b,
-        name: "G933 Headset",
-    },
-    DeviceMatch {
-        pid: 0x0a66,
-        name: "G533 Headset",
-    },
-];
+/// Information about a supported device
+pub struct DeviceMatch {
+    /// USB product ID
+    pub pid: u16,
+    /// Model the product ID belongs to
+    pub model: &'static registry::DeviceModel,
+    /// How the headset is connected when it has this product ID
+    pub connection: registry::Connection,
+}
 
 /// Static reference to information about a supported device
-pub type StaticDeviceMatch = &'static DeviceMatch<'static>;
+pub type StaticDeviceMatch = &'static DeviceMatch;
 
 fn match_device<'a>(dev: &'a udev::Device) -> Option<StaticDeviceMatch> {
     let pid = dev.attribute_value("idProduct")
         .and_then(|s| s.to_str())
         .and_then(|s| u16::from_str_radix(s, 16).ok());
-    return pid.and_then(|pid| SUPPORTED_DEVICES.iter().find(|dev| dev.pid == pid));
+    return pid.and_then(registry::find);
+}
+
+/// Pick the hidraw node of the HID++ interface out of a USB device's hidraw devices, going by
+/// the reports each one's report descriptor declares. Composite devices also have audio control
+/// and consumer interfaces, which don't answer HID++ requests
+fn find_hidpp_node<I: Iterator<Item = udev::Device>>(hidraws: I) -> Result<PathBuf, Error> {
+    let mut candidates = Vec::new();
+    for hidraw in hidraws {
+        let node = match hidraw.devnode() {
+            Some(node) => node.to_path_buf(),
+            None => continue,
+        };
+        let descriptor_path = hidraw.syspath().join("device/report_descriptor");
+        match fs::read(&descriptor_path) {
+            Ok(ref descriptor) if descriptor::is_hidpp(descriptor) => {
+                debug!("Using {} for HID++", node.display());
+                return Ok(node);
+            }
+            Ok(descriptor) => {
+                let reports = descriptor::reports(&descriptor)
+                    .iter()
+                    .map(|report| format!("{:#04x}", report.id))
+                    .collect::<Vec<_>>();
+                candidates.push(if reports.is_empty() {
+                    format!("{} (no report IDs)", node.display())
+                } else {
+                    format!("{} (reports {})", node.display(), reports.join(", "))
+                });
+            }
+            Err(error) => candidates.push(format!(
+                "{} ({}: {})",
+                node.display(),
+                descriptor_path.display(),
+                error
+            )),
+        }
+    }
+
+    ensure!(
+        !candidates.is_empty(),
+        "Parent does not contain any hidraw devices"
+    );
+    bail!(
+        "No hidraw device declares the HID++ reports, candidates: {}",
+        candidates.join("; ")
+    )
 }
 
-/// Enumerate and initialize devices
+/// Enumerate and initialize devices, failing with the reasons if some were found but none can be
+/// used
 pub fn find_devices() -> Result<HashMap<String, Device>, Error> {
     let context = udev::Context::new()?;
 
@@ -521,33 +1075,167 @@ pub fn find_devices() -> Result<HashMap<String, Device>, Error> {
     let parents = enumerator.scan_devices()?;
 
     let mut devices = HashMap::new();
+    let mut errors = Vec::new();
     for parent in parents {
         let dev_match = match match_device(&parent) {
             Some(dev_match) => dev_match,
             None => continue,
         };
         info!(
-            "Found usb device: {} ({})",
+            "Found usb device: {} ({}, {:?})",
             parent.sysname().to_str().unwrap(),
-            dev_match.name
+            dev_match.model.name,
+            dev_match.connection
         );
 
         let mut enumerator = udev::Enumerator::new(&context)?;
         enumerator.match_subsystem("hidraw")?;
         enumerator.match_parent(&parent)?;
-        devices.insert(
-            parent.sysname().to_string_lossy().to_string(),
-            Device::new(
-                enumerator
-                    .scan_devices()?
-                    .next()
-                    .ok_or_else(|| format_err!("Parent does not contain any hidraw devices"))?
-                    .devnode()
-                    .ok_or_else(|| format_err!("Hidraw device does not have a filesystem node"))?,
-                dev_match,
-            )?,
-        );
+        let sysname = parent.sysname().to_string_lossy().to_string();
+        let path = match find_hidpp_node(enumerator.scan_devices()?) {
+            Ok(path) => path,
+            Err(error) => {
+                // One odd device shouldn't keep the others from being found, but if none are
+                // usable the user needs to know why
+                warn!("Skipping {}: {}", sysname, error);
+                errors.push(format!("{}: {}", sysname, error));
+                continue;
+            }
+        };
+        devices.insert(sysname, Device::new(&path, dev_match)?);
     }
 
+    ensure!(
+        !devices.is_empty() || errors.is_empty(),
+        "No usable devices found. {}",
+        errors.join(". ")
+    );
     Ok(devices)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use battery::{BatteryStatus, ChargingStatus, PowerEvent};
+    use std::os::unix::io::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Index the fake headset gives the battery feature
+    const BATTERY_INDEX: u8 = 0x05;
+
+    /// A G933 that answers battery requests while it is on, and can send battery notifications
+    struct FakeHeadset {
+        socket: UnixDatagram,
+        on: Arc<AtomicBool>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl FakeHeadset {
+        fn start() -> (Self, Device) {
+            let (ours, theirs) = UnixDatagram::pair().unwrap();
+            let on = Arc::new(AtomicBool::new(true));
+            let requests = Arc::new(AtomicUsize::new(0));
+
+            let socket = ours.try_clone().unwrap();
+            let (thread_on, thread_requests) = (Arc::clone(&on), Arc::clone(&requests));
+            thread::spawn(move || {
+                let mut request = [0u8; 20];
+                while socket.recv(&mut request).is_ok() {
+                    thread_requests.fetch_add(1, Ordering::SeqCst);
+                    if !thread_on.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let mut response = request;
+                    match (request[2], request[3] & 0xf0) {
+                        // Only the battery feature is there
+                        (0x00, 0x00) => {
+                            let feature = BigEndian::read_u16(&request[4..6]);
+                            response[4] = if feature == FEATURE_BATTERY {
+                                BATTERY_INDEX
+                            } else {
+                                0
+                            };
+                        }
+                        // Discharging at 3.9 V
+                        (BATTERY_INDEX, 0x00) => response[4..7].copy_from_slice(&[0x0f, 0x3c, 1]),
+                        _ => continue,
+                    }
+                    socket.send(&response).unwrap();
+                }
+            });
+
+            let dev_match = registry::find(0x0a5b).unwrap();
+            let device = Device::from_file(File::from(OwnedFd::from(theirs)), dev_match).unwrap();
+            let headset = Self {
+                socket: ours,
+                on,
+                requests,
+            };
+            (headset, device)
+        }
+
+        /// Send a battery notification once the device had time to start waiting for it
+        fn notify_soon(&self, status: &[u8; 3]) {
+            let socket = self.socket.try_clone().unwrap();
+            let mut notification = [0u8; 20];
+            notification[..4].copy_from_slice(&[0x11, 0xff, BATTERY_INDEX, 0x00]);
+            notification[4..7].copy_from_slice(status);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                socket.send(&notification).unwrap();
+            });
+        }
+    }
+
+    #[test]
+    fn waits_for_headset_to_come_back_on() {
+        let (headset, mut device) = FakeHeadset::start();
+        let timeout = Duration::from_secs(2);
+
+        assert!(device
+            .wait_for_power_events(Duration::from_millis(100))
+            .unwrap()
+            .is_empty());
+
+        headset.on.store(false, Ordering::SeqCst);
+        headset.notify_soon(&[0, 0, 0]);
+        let events = device.wait_for_power_events(timeout).unwrap();
+        assert!(matches!(events[..], [PowerEvent::PoweredOff]));
+
+        // Nothing is asked of the headset while it's off, so waiting doesn't block
+        let requests = headset.requests.load(Ordering::SeqCst);
+        let start = Instant::now();
+        assert!(device
+            .wait_for_power_events(Duration::from_millis(100))
+            .unwrap()
+            .is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Turned on with the charger plugged in while it was off, which we can't know about
+        headset.notify_soon(&[0x0f, 0xa0, 3]);
+        let events = device.wait_for_power_events(timeout).unwrap();
+        assert!(matches!(
+            events[..],
+            [PowerEvent::StatusChanged(BatteryStatus {
+                charging_status: ChargingStatus::Charging(_),
+                ..
+            })]
+        ));
+
+        // But the charger being unplugged after that is seen again
+        headset.notify_soon(&[0x0f, 0x3c, 1]);
+        let events = device.wait_for_power_events(timeout).unwrap();
+        assert!(matches!(
+            events[..],
+            [
+                PowerEvent::ChargerDisconnected,
+                PowerEvent::StatusChanged(BatteryStatus {
+                    charging_status: ChargingStatus::Discharging,
+                    ..
+                })
+            ]
+        ));
+        assert_eq!(headset.requests.load(Ordering::SeqCst), requests);
+    }
+}
@@ -1,11 +1,16 @@
 //! Configuration structs and stuff for headset lighting
 
 use byteorder::{BigEndian, ByteOrder};
+use failure::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
-use {AsBytes, FromBytes};
+use {AsBytes, FromBytes, TryFromBytes};
 
 /// Describes which light to configure
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Light {
     /// The logo light
     Logo,
@@ -13,52 +18,395 @@ pub enum Light {
     Side,
 }
 
+impl FromStr for Light {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "logo" => Ok(Light::Logo),
+            "side" => Ok(Light::Side),
+            s => bail!("Invalid light: {}", s),
+        }
+    }
+}
+
+/// A color to show on a light
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color {
+    /// Red value
+    pub red: u8,
+    /// Green value
+    pub green: u8,
+    /// Blue value
+    pub blue: u8,
+}
+
+impl Color {
+    /// Create a color from red, green and blue values
+    pub fn new(red: u8, green: u8, blue: u8) -> Self {
+        Self { red, green, blue }
+    }
+
+    /// Create a color from a hue in degrees, and saturation and value from 0 to 1
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32) -> Self {
+        let chroma = value * saturation;
+        Self::from_hue_chroma(hue, chroma, value - chroma)
+    }
+
+    /// Create a color from a hue in degrees, and saturation and lightness from 0 to 1
+    pub fn from_hsl(hue: f32, saturation: f32, lightness: f32) -> Self {
+        let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+        Self::from_hue_chroma(hue, chroma, lightness - chroma / 2.0)
+    }
+
+    /// Create the color of a black body at the given temperature in Kelvin, which should be
+    /// between 1000 and 40000
+    pub fn from_temperature(kelvin: f32) -> Self {
+        // Tanner Helland's curve fit of the black body colors
+        let t = kelvin / 100.0;
+        let red = if t <= 66.0 {
+            255.0
+        } else {
+            329.698_73 * (t - 60.0).powf(-0.133_204_76)
+        };
+        let green = if t <= 66.0 {
+            99.470_8 * t.ln() - 161.119_57
+        } else {
+            288.122_17 * (t - 60.0).powf(-0.075_514_85)
+        };
+        let blue = if t >= 66.0 {
+            255.0
+        } else if t <= 19.0 {
+            0.0
+        } else {
+            138.517_73 * (t - 10.0).ln() - 305.044_8
+        };
+        let channel = |c: f32| c.clamp(0.0, 255.0).round() as u8;
+        Self::new(channel(red), channel(green), channel(blue))
+    }
+
+    fn from_hue_chroma(hue: f32, chroma: f32, lightness: f32) -> Self {
+        let hue = (hue / 60.0).rem_euclid(6.0);
+        let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+        let (r, g, b) = match hue as u8 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let channel = |c: f32| ((c + lightness).clamp(0.0, 1.0) * 255.0).round() as u8;
+        Self::new(channel(r), channel(g), channel(b))
+    }
+
+    /// Mix two colors, going `t` (from 0 to 1) of the way from this one to the other. Mixing is
+    /// done in the Oklab color space, so the steps in between look evenly spaced
+    pub fn mix(self, other: Color, t: f32) -> Self {
+        let from = self.to_oklab();
+        let to = other.to_oklab();
+        let t = f64::from(t.clamp(0.0, 1.0));
+        Self::from_oklab([
+            from[0] + (to[0] - from[0]) * t,
+            from[1] + (to[1] - from[1]) * t,
+            from[2] + (to[2] - from[2]) * t,
+        ])
+    }
+
+    /// Convert to Oklab lightness and a/b components
+    fn to_oklab(self) -> [f64; 3] {
+        let linear = |c: u8| {
+            let c = f64::from(c) / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        let (r, g, b) = (linear(self.red), linear(self.green), linear(self.blue));
+
+        let l = (0.412_221_470_8 * r + 0.536_332_536_3 * g + 0.051_445_992_9 * b).cbrt();
+        let m = (0.211_903_498_2 * r + 0.680_699_545_1 * g + 0.107_396_956_6 * b).cbrt();
+        let s = (0.088_302_461_9 * r + 0.281_718_837_6 * g + 0.629_978_700_5 * b).cbrt();
+
+        [
+            0.210_454_255_3 * l + 0.793_617_785_0 * m - 0.004_072_046_8 * s,
+            1.977_998_495_1 * l - 2.428_592_205_0 * m + 0.450_593_709_9 * s,
+            0.025_904_037_1 * l + 0.782_771_766_2 * m - 0.808_675_766_0 * s,
+        ]
+    }
+
+    /// Convert from Oklab lightness and a/b components
+    fn from_oklab(lab: [f64; 3]) -> Self {
+        let l = (lab[0] + 0.396_337_777_4 * lab[1] + 0.215_803_757_3 * lab[2]).powi(3);
+        let m = (lab[0] - 0.105_561_345_8 * lab[1] - 0.063_854_172_8 * lab[2]).powi(3);
+        let s = (lab[0] - 0.089_484_177_5 * lab[1] - 1.291_485_548_0 * lab[2]).powi(3);
+
+        let srgb = |c: f64| {
+            let c = if c <= 0.003_130_8 {
+                c * 12.92
+            } else {
+                1.055 * c.powf(1.0 / 2.4) - 0.055
+            };
+            (c.clamp(0.0, 1.0) * 255.0).round() as u8
+        };
+        Self::new(
+            srgb(4.076_741_662_1 * l - 3.307_711_591_3 * m + 0.230_969_929_2 * s),
+            srgb(-1.268_438_004_6 * l + 2.609_757_401_1 * m - 0.341_319_396_5 * s),
+            srgb(-0.004_196_086_3 * l - 0.703_418_614_7 * m + 1.707_614_701_0 * s),
+        )
+    }
+
+    /// Adjust a color so it looks as intended on LEDs with the given correction
+    pub fn corrected(self, correction: &Correction) -> Self {
+        let channel = |c: u8, scale: f32| {
+            ((f32::from(c) / 255.0).powf(correction.gamma) * scale * 255.0).round() as u8
+        };
+        Self::new(
+            channel(self.red, correction.white.0),
+            channel(self.green, correction.white.1),
+            channel(self.blue, correction.white.2),
+        )
+    }
+}
+
+impl AsBytes for Color {
+    fn as_bytes(&self) -> Vec<u8> {
+        vec![self.red, self.green, self.blue]
+    }
+}
+
+impl FromBytes for Color {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self::new(bytes[0], bytes[1], bytes[2])
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+impl FromStr for Color {
+    type Err = Error;
+
+    /// Parse a color given as `#rrggbb`, `#rgb`, a CSS color name, `hsv(h, s%, v%)`,
+    /// `hsl(h, s%, l%)` or a temperature like `6500K`
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let s = s.trim().to_lowercase();
+
+        if let Some(hex) = s.strip_prefix('#') {
+            // from_str_radix would take a sign too
+            ensure!(
+                hex.chars().all(|c| c.is_ascii_hexdigit()),
+                "Invalid hex color: {}",
+                s
+            );
+            let value = u32::from_str_radix(hex, 16)
+                .map_err(|_| format_err!("Invalid hex color: {}", s))?;
+            return match hex.len() {
+                6 => Ok(Self::new(
+                    (value >> 16) as u8,
+                    (value >> 8) as u8,
+                    value as u8,
+                )),
+                // Each digit is doubled, so #f80 is #ff8800
+                3 => Ok(Self::new(
+                    ((value >> 8) & 0xf) as u8 * 0x11,
+                    ((value >> 4) & 0xf) as u8 * 0x11,
+                    (value & 0xf) as u8 * 0x11,
+                )),
+                _ => bail!("Hex colors must be given as #rrggbb or #rgb: was {}", s),
+            };
+        }
+
+        if let Some(kelvin) = s.strip_suffix('k') {
+            if let Ok(kelvin) = kelvin.trim().parse::<f32>() {
+                ensure!(
+                    (1000.0..=40000.0).contains(&kelvin),
+                    "Color temperature must be between 1000K and 40000K: was {}K",
+                    kelvin
+                );
+                return Ok(Self::from_temperature(kelvin));
+            }
+        }
+
+        if let Some(args) = s.strip_prefix("hsv(").and_then(|s| s.strip_suffix(')')) {
+            let (hue, saturation, value) = parse_hue_args(args)?;
+            return Ok(Self::from_hsv(hue, saturation, value));
+        }
+
+        if let Some(args) = s.strip_prefix("hsl(").and_then(|s| s.strip_suffix(')')) {
+            let (hue, saturation, lightness) = parse_hue_args(args)?;
+            return Ok(Self::from_hsl(hue, saturation, lightness));
+        }
+
+        NAMED_COLORS
+            .iter()
+            .find(|&&(name, _)| name == s)
+            .map(|&(_, value)| Self::new((value >> 16) as u8, (value >> 8) as u8, value as u8))
+            .ok_or_else(|| format_err!("Invalid color: {}", s))
+    }
+}
+
+/// Parse the arguments of `hsv(...)` or `hsl(...)`: a hue in degrees followed by two
+/// percentages (or fractions from 0 to 1)
+fn parse_hue_args(args: &str) -> Result<(f32, f32, f32), Error> {
+    let args = args.split(',').map(str::trim).collect::<Vec<_>>();
+    ensure!(
+        args.len() == 3,
+        "Expected 3 color components: was {}",
+        args.len()
+    );
+
+    let hue = args[0].trim_end_matches("deg").parse::<f32>()?;
+    let fraction = |arg: &str| -> Result<f32, Error> {
+        let value = match arg.strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f32>()? / 100.0,
+            None => arg.parse::<f32>()?,
+        };
+        ensure!(
+            (0.0..=1.0).contains(&value),
+            "Color component out of range: was {}",
+            arg
+        );
+        Ok(value)
+    };
+
+    Ok((hue, fraction(args[1])?, fraction(args[2])?))
+}
+
+/// Adjustments needed to make colors look right on a device's LEDs
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Correction {
+    /// Gamma to apply to each channel
+    pub gamma: f32,
+    /// Scale of the red, green and blue channels after gamma, so that white looks white
+    pub white: (f32, f32, f32),
+}
+
+/// Correction that leaves colors alone
+pub const NO_CORRECTION: Correction = Correction {
+    gamma: 1.0,
+    white: (1.0, 1.0, 1.0),
+};
+
+/// Longest period of breathing and color cycle effects that fits in the 16 bits of milliseconds
+/// it is sent as. The device doesn't report which periods it supports
+pub const MAX_PERIOD: Duration = Duration::from_millis(u16::MAX as u64);
+
+/// Period of an effect as sent to the device, in milliseconds
+fn period_millis(period: Duration) -> u16 {
+    period.as_millis().min(u128::from(u16::MAX)) as u16
+}
+
 /// Configuration for the light effect
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Effect {
     /// Settings for the off effect
     Off,
     /// Settings for the static color effect
     Static {
-        /// Red value
-        red: u8,
-        /// Blue value
-        green: u8,
-        /// Green value
-        blue: u8,
+        /// Light color
+        color: Color,
     },
     /// Settings for the breathing effect
     Breathing {
-        /// Red value
-        red: u8,
-        /// Green value
-        green: u8,
-        /// Blue value
-        blue: u8,
-        /// The rate of the breathing effect
-        rate: u16,
-        /// Light brightness
+        /// Light color
+        color: Color,
+        /// Time for one breath
+        period: Duration,
+        /// Light brightness, in percent
         brightness: u8,
     },
     /// Settings for the color cycle effect
     ColorCycle {
-        /// The rate of the cycle effect
-        rate: u16,
-        /// Light brightness
+        /// Time for one trip around the color wheel
+        period: Duration,
+        /// Light brightness, in percent
         brightness: u8,
     },
 }
 
-/// Profile type (default or not)
-/// TODO: figure out more about this
-#[derive(Debug)]
+impl Effect {
+    /// Adjust the color of the effect (if it has one) with a color correction
+    pub fn corrected(self, correction: &Correction) -> Self {
+        match self {
+            Effect::Static { color } => Effect::Static {
+                color: color.corrected(correction),
+            },
+            Effect::Breathing {
+                color,
+                period,
+                brightness,
+            } => Effect::Breathing {
+                color: color.corrected(correction),
+                period,
+                brightness,
+            },
+            effect => effect,
+        }
+    }
+
+    /// Check that the period and brightness of the effect can be sent to the device as they are,
+    /// for checking effects given by the user
+    pub fn validate(&self) -> Result<(), Error> {
+        match *self {
+            Effect::Breathing {
+                period, brightness, ..
+            }
+            | Effect::ColorCycle { period, brightness } => {
+                ensure!(
+                    period >= Duration::from_millis(1) && period <= MAX_PERIOD,
+                    "Effect period must be between 1ms and {:?}: was {:?}",
+                    MAX_PERIOD,
+                    period
+                );
+                ensure!(
+                    brightness <= 100,
+                    "Brightness must be at most 100%: was {}%",
+                    brightness
+                );
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Which of the device's profiles a light configuration is written to
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ProfileType {
-    /// Temporarily set (until next power-on)
+    /// Shown right away, until the next power-on
     Temporary,
-    /// Permanently store setting in device (don't apply now)
+    /// Stored onboard as the power-on default, without changing what is shown now
     Permanent,
 }
 
+/// Where a light configuration should take effect
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// Apply right away, until the next power-on
+    Now,
+    /// Store as the power-on default, so it survives use without our tools
+    PowerOn,
+    /// Apply right away and store as the power-on default
+    Both,
+}
+
+impl Target {
+    /// Profiles that need to be written to reach this target
+    pub fn profile_types(self) -> &'static [ProfileType] {
+        match self {
+            Target::Now => &[ProfileType::Temporary],
+            Target::PowerOn => &[ProfileType::Permanent],
+            Target::Both => &[ProfileType::Temporary, ProfileType::Permanent],
+        }
+    }
+}
+
 /// Headset light configuration
 #[derive(Debug)]
 pub struct Config {
@@ -66,7 +414,7 @@ pub struct Config {
     pub light: Light,
     /// Configuration for the effect
     pub effect: Effect,
-    /// Profile type - unknown exactly how this works, but 2 seems to be the "device profile" and 0 non-default
+    /// Profile the configuration is written to or was read from
     pub profile_type: ProfileType,
 }
 
@@ -88,26 +436,20 @@ impl AsBytes for Config {
 
         match self.effect {
             Effect::Off => (),
-            Effect::Static { red, green, blue } => {
-                params[2] = red;
-                params[3] = green;
-                params[4] = blue;
+            Effect::Static { color } => {
+                params[2..5].copy_from_slice(&color.as_bytes());
             }
             Effect::Breathing {
-                red,
-                green,
-                blue,
-                rate,
+                color,
+                period,
                 brightness,
             } => {
-                params[2] = red;
-                params[3] = green;
-                params[4] = blue;
-                BigEndian::write_u16(&mut params[5..7], rate);
+                params[2..5].copy_from_slice(&color.as_bytes());
+                BigEndian::write_u16(&mut params[5..7], period_millis(period));
                 params[8] = brightness;
             }
-            Effect::ColorCycle { rate, brightness } => {
-                BigEndian::write_u16(&mut params[7..9], rate);
+            Effect::ColorCycle { period, brightness } => {
+                BigEndian::write_u16(&mut params[7..9], period_millis(period));
                 params[9] = brightness;
             }
         }
@@ -121,25 +463,30 @@ impl AsBytes for Config {
     }
 }
 
-impl FromBytes for Config {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(
+impl TryFromBytes for Config {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        ensure!(
+            bytes.len() >= 13,
+            "Light configuration is too short: was {} bytes",
+            bytes.len()
+        );
+        ensure!(
             bytes[0] <= 1,
             "Light index is out of range: was {}",
             bytes[0]
         );
-        assert!(
+        ensure!(
             bytes[1] <= 3,
             "Light effect is out of range: was {}",
             bytes[1]
         );
-        assert!(
+        ensure!(
             bytes[12] == 0 || bytes[12] == 2,
             "Light profile type was out of range: was {}",
             bytes[12]
         );
 
-        Self {
+        Ok(Self {
             light: match bytes[0] {
                 0 => Light::Logo,
                 1 => Light::Side,
@@ -148,19 +495,15 @@ impl FromBytes for Config {
             effect: match bytes[1] {
                 0 => Effect::Off,
                 1 => Effect::Static {
-                    red: bytes[2],
-                    green: bytes[3],
-                    blue: bytes[4],
+                    color: Color::from_bytes(&bytes[2..5]),
                 },
                 2 => Effect::Breathing {
-                    red: bytes[2],
-                    green: bytes[3],
-                    blue: bytes[4],
-                    rate: BigEndian::read_u16(&bytes[5..7]),
+                    color: Color::from_bytes(&bytes[2..5]),
+                    period: Duration::from_millis(BigEndian::read_u16(&bytes[5..7]).into()),
                     brightness: bytes[8],
                 },
                 3 => Effect::ColorCycle {
-                    rate: BigEndian::read_u16(&bytes[7..9]),
+                    period: Duration::from_millis(BigEndian::read_u16(&bytes[7..9]).into()),
                     brightness: bytes[9],
                 },
                 _ => unreachable!(),
@@ -170,6 +513,291 @@ impl FromBytes for Config {
                 2 => ProfileType::Permanent,
                 _ => unreachable!(),
             },
+        })
+    }
+}
+
+/// Notification sent by the headset when an effect starts a new cycle
+#[derive(Debug)]
+pub struct SyncEvent {
+    /// The light running the effect
+    pub light: Light,
+    /// Seems to be the effect rate minus one, but could be a countdown of some sort
+    pub rate: u16,
+}
+
+impl TryFromBytes for SyncEvent {
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        ensure!(
+            bytes.len() >= 3,
+            "Lighting sync event is too short: was {} bytes",
+            bytes.len()
+        );
+        ensure!(
+            bytes[0] <= 1,
+            "Light index is out of range: was {}",
+            bytes[0]
+        );
+
+        Ok(Self {
+            light: match bytes[0] {
+                0 => Light::Logo,
+                1 => Light::Side,
+                _ => unreachable!(),
+            },
+            rate: BigEndian::read_u16(&bytes[1..3]),
+        })
+    }
+}
+
+/// CSS named colors
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xF0F8FF),
+    ("antiquewhite", 0xFAEBD7),
+    ("aqua", 0x00FFFF),
+    ("aquamarine", 0x7FFFD4),
+    ("azure", 0xF0FFFF),
+    ("beige", 0xF5F5DC),
+    ("bisque", 0xFFE4C4),
+    ("black", 0x000000),
+    ("blanchedalmond", 0xFFEBCD),
+    ("blue", 0x0000FF),
+    ("blueviolet", 0x8A2BE2),
+    ("brown", 0xA52A2A),
+    ("burlywood", 0xDEB887),
+    ("cadetblue", 0x5F9EA0),
+    ("chartreuse", 0x7FFF00),
+    ("chocolate", 0xD2691E),
+    ("coral", 0xFF7F50),
+    ("cornflowerblue", 0x6495ED),
+    ("cornsilk", 0xFFF8DC),
+    ("crimson", 0xDC143C),
+    ("cyan", 0x00FFFF),
+    ("darkblue", 0x00008B),
+    ("darkcyan", 0x008B8B),
+    ("darkgoldenrod", 0xB8860B),
+    ("darkgray", 0xA9A9A9),
+    ("darkgreen", 0x006400),
+    ("darkgrey", 0xA9A9A9),
+    ("darkkhaki", 0xBDB76B),
+    ("darkmagenta", 0x8B008B),
+    ("darkolivegreen", 0x556B2F),
+    ("darkorange", 0xFF8C00),
+    ("darkorchid", 0x9932CC),
+    ("darkred", 0x8B0000),
+    ("darksalmon", 0xE9967A),
+    ("darkseagreen", 0x8FBC8F),
+    ("darkslateblue", 0x483D8B),
+    ("darkslategray", 0x2F4F4F),
+    ("darkslategrey", 0x2F4F4F),
+    ("darkturquoise", 0x00CED1),
+    ("darkviolet", 0x9400D3),
+    ("deeppink", 0xFF1493),
+    ("deepskyblue", 0x00BFFF),
+    ("dimgray", 0x696969),
+    ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1E90FF),
+    ("firebrick", 0xB22222),
+    ("floralwhite", 0xFFFAF0),
+    ("forestgreen", 0x228B22),
+    ("fuchsia", 0xFF00FF),
+    ("gainsboro", 0xDCDCDC),
+    ("ghostwhite", 0xF8F8FF),
+    ("gold", 0xFFD700),
+    ("goldenrod", 0xDAA520),
+    ("gray", 0x808080),
+    ("green", 0x008000),
+    ("greenyellow", 0xADFF2F),
+    ("grey", 0x808080),
+    ("honeydew", 0xF0FFF0),
+    ("hotpink", 0xFF69B4),
+    ("indianred", 0xCD5C5C),
+    ("indigo", 0x4B0082),
+    ("ivory", 0xFFFFF0),
+    ("khaki", 0xF0E68C),
+    ("lavender", 0xE6E6FA),
+    ("lavenderblush", 0xFFF0F5),
+    ("lawngreen", 0x7CFC00),
+    ("lemonchiffon", 0xFFFACD),
+    ("lightblue", 0xADD8E6),
+    ("lightcoral", 0xF08080),
+    ("lightcyan", 0xE0FFFF),
+    ("lightgoldenrodyellow", 0xFAFAD2),
+    ("lightgray", 0xD3D3D3),
+    ("lightgreen", 0x90EE90),
+    ("lightgrey", 0xD3D3D3),
+    ("lightpink", 0xFFB6C1),
+    ("lightsalmon", 0xFFA07A),
+    ("lightseagreen", 0x20B2AA),
+    ("lightskyblue", 0x87CEFA),
+    ("lightslategray", 0x778899),
+    ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xB0C4DE),
+    ("lightyellow", 0xFFFFE0),
+    ("lime", 0x00FF00),
+    ("limegreen", 0x32CD32),
+    ("linen", 0xFAF0E6),
+    ("magenta", 0xFF00FF),
+    ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66CDAA),
+    ("mediumblue", 0x0000CD),
+    ("mediumorchid", 0xBA55D3),
+    ("mediumpurple", 0x9370DB),
+    ("mediumseagreen", 0x3CB371),
+    ("mediumslateblue", 0x7B68EE),
+    ("mediumspringgreen", 0x00FA9A),
+    ("mediumturquoise", 0x48D1CC),
+    ("mediumvioletred", 0xC71585),
+    ("midnightblue", 0x191970),
+    ("mintcream", 0xF5FFFA),
+    ("mistyrose", 0xFFE4E1),
+    ("moccasin", 0xFFE4B5),
+    ("navajowhite", 0xFFDEAD),
+    ("navy", 0x000080),
+    ("oldlace", 0xFDF5E6),
+    ("olive", 0x808000),
+    ("olivedrab", 0x6B8E23),
+    ("orange", 0xFFA500),
+    ("orangered", 0xFF4500),
+    ("orchid", 0xDA70D6),
+    ("palegoldenrod", 0xEEE8AA),
+    ("palegreen", 0x98FB98),
+    ("paleturquoise", 0xAFEEEE),
+    ("palevioletred", 0xDB7093),
+    ("papayawhip", 0xFFEFD5),
+    ("peachpuff", 0xFFDAB9),
+    ("peru", 0xCD853F),
+    ("pink", 0xFFC0CB),
+    ("plum", 0xDDA0DD),
+    ("powderblue", 0xB0E0E6),
+    ("purple", 0x800080),
+    ("rebeccapurple", 0x663399),
+    ("red", 0xFF0000),
+    ("rosybrown", 0xBC8F8F),
+    ("royalblue", 0x4169E1),
+    ("saddlebrown", 0x8B4513),
+    ("salmon", 0xFA8072),
+    ("sandybrown", 0xF4A460),
+    ("seagreen", 0x2E8B57),
+    ("seashell", 0xFFF5EE),
+    ("sienna", 0xA0522D),
+    ("silver", 0xC0C0C0),
+    ("skyblue", 0x87CEEB),
+    ("slateblue", 0x6A5ACD),
+    ("slategray", 0x708090),
+    ("slategrey", 0x708090),
+    ("snow", 0xFFFAFA),
+    ("springgreen", 0x00FF7F),
+    ("steelblue", 0x4682B4),
+    ("tan", 0xD2B48C),
+    ("teal", 0x008080),
+    ("thistle", 0xD8BFD8),
+    ("tomato", 0xFF6347),
+    ("turquoise", 0x40E0D0),
+    ("violet", 0xEE82EE),
+    ("wheat", 0xF5DEB3),
+    ("white", 0xFFFFFF),
+    ("whitesmoke", 0xF5F5F5),
+    ("yellow", 0xFFFF00),
+    ("yellowgreen", 0x9ACD32),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(s: &str) -> Color {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_hex() {
+        assert_eq!(color("#ff8800"), Color::new(0xff, 0x88, 0x00));
+        assert_eq!(color("#FF8800"), Color::new(0xff, 0x88, 0x00));
+        assert_eq!(color(" #0a0b0c "), Color::new(0x0a, 0x0b, 0x0c));
+        assert_eq!(color("#f80"), Color::new(0xff, 0x88, 0x00));
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        for s in &[
+            "#+ff", "#+ffffe", "#-fffff", "#ff 800", "#fg0000", "#12345", "#", "ff8800",
+        ] {
+            assert!(s.parse::<Color>().is_err(), "{} was accepted", s);
         }
     }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(color("rebeccapurple"), Color::new(0x66, 0x33, 0x99));
+        assert_eq!(color("Red"), Color::new(0xff, 0, 0));
+        assert!("notacolor".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn parses_hsv_and_hsl() {
+        assert_eq!(color("hsv(0, 100%, 100%)"), Color::new(255, 0, 0));
+        assert_eq!(color("hsv(120deg, 1, 0.5)"), Color::new(0, 128, 0));
+        assert_eq!(color("hsv(-120, 100%, 100%)"), Color::new(0, 0, 255));
+        assert_eq!(color("hsl(60, 100%, 50%)"), Color::new(255, 255, 0));
+        assert_eq!(color("hsl(0, 0%, 100%)"), Color::new(255, 255, 255));
+        assert!("hsv(0, 150%, 100%)".parse::<Color>().is_err());
+        assert!("hsl(0, 100%)".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn parses_temperatures() {
+        assert_eq!(color("6500K"), Color::new(255, 254, 250));
+        assert_eq!(color("1000k"), Color::new(255, 68, 0));
+        assert!("500K".parse::<Color>().is_err());
+        assert!("50000K".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn reads_config_from_device() {
+        let config = Config {
+            light: Light::Side,
+            effect: Effect::Breathing {
+                color: Color::new(1, 2, 3),
+                period: Duration::from_millis(5000),
+                brightness: 80,
+            },
+            profile_type: ProfileType::Permanent,
+        };
+        let read = Config::try_from_bytes(&config.as_bytes()).unwrap();
+        assert_eq!(
+            (read.light, read.effect, read.profile_type),
+            (config.light, config.effect, config.profile_type)
+        );
+    }
+
+    #[test]
+    fn rejects_unexpected_configs() {
+        let mut bytes = vec![0u8; 13];
+        assert!(Config::try_from_bytes(&bytes).is_ok());
+        bytes[0] = 2;
+        assert!(Config::try_from_bytes(&bytes).is_err());
+        bytes[0] = 0;
+        bytes[1] = 4;
+        assert!(Config::try_from_bytes(&bytes).is_err());
+        bytes[1] = 0;
+        bytes[12] = 1;
+        assert!(Config::try_from_bytes(&bytes).is_err());
+        assert!(Config::try_from_bytes(&bytes[..12]).is_err());
+    }
+
+    #[test]
+    fn reads_sync_events() {
+        let event = SyncEvent::try_from_bytes(&[0x01, 0x13, 0x87]).unwrap();
+        assert_eq!((event.light, event.rate), (Light::Side, 0x1387));
+        assert!(SyncEvent::try_from_bytes(&[0x02, 0x13, 0x87]).is_err());
+        assert!(SyncEvent::try_from_bytes(&[0x01]).is_err());
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        let original = Color::new(0x12, 0xab, 0xef);
+        assert_eq!(original.to_string(), "#12abef");
+        assert_eq!(color(&original.to_string()), original);
+    }
 }
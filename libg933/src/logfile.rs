@@ -0,0 +1,107 @@
+//! Append-only log files that rotate once they get too big
+
+use failure::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Log file that is moved aside to `<path>.1` once it reaches a maximum size, with older files
+/// shifted along to `<path>.2` and so on up to a number of files to keep
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    file: Option<File>,
+}
+
+/// Files of a rotated log that exist, oldest first
+pub fn existing_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    for number in 1.. {
+        let file = numbered(path, number);
+        if !file.exists() {
+            break;
+        }
+        files.push(file);
+    }
+    files.reverse();
+    files
+}
+
+impl RotatingFile {
+    /// Create a log at `path`, creating its directory if needed. Lines are appended to any
+    /// existing file
+    pub fn new(path: &Path, max_size: u64, keep: u32) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file: None,
+        })
+    }
+
+    /// Path of the current log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a line to the log, rotating it first if the line would make it too big
+    pub fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(error.into()),
+        };
+        if size > 0 && size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+
+        if self.file.is_none() {
+            self.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}", line)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Shift every file along by one, dropping the oldest
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.file = None;
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+
+        for number in (1..self.keep).rev() {
+            let from = numbered(&self.path, number);
+            if from.exists() {
+                fs::rename(&from, numbered(&self.path, number + 1))?;
+            }
+        }
+        fs::rename(&self.path, numbered(&self.path, 1))?;
+
+        debug!("Rotated log file {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// Path of an old log file, like `<path>.1`
+fn numbered(path: &Path, number: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", number));
+    PathBuf::from(name)
+}
@@ -0,0 +1,359 @@
+//! Desktop notifications about the battery, sent over D-Bus
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::Connection;
+use failure::Error;
+use std::time::Duration;
+
+use battery::{BatteryStatus, ChargingStatus};
+
+/// Name the notifications are sent under
+const APP_NAME: &str = "g933-utils";
+
+/// How long to wait for the notification server to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something about the battery worth telling the user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alert {
+    /// The charge dropped below one of the thresholds
+    Low {
+        /// Threshold that was crossed
+        threshold: f32,
+        /// Charge percentage when it was crossed
+        charge: f32,
+        /// Whether it was the lowest threshold
+        critical: bool,
+    },
+    /// The battery finished charging
+    Charged,
+}
+
+/// Follows battery statuses to tell when the charge drops below a threshold or charging
+/// completes, so each is only alerted once
+#[derive(Debug, Clone)]
+pub struct BatteryAlerts {
+    /// Charge percentages to alert at, highest first
+    thresholds: Vec<f32>,
+    /// Lowest threshold already alerted since the charger was last unplugged
+    alerted: Option<f32>,
+    /// Charging status of the last battery status
+    last_status: Option<ChargingStatus>,
+}
+
+impl BatteryAlerts {
+    /// Alert when the charge drops below each of the given percentages
+    pub fn new(thresholds: &[f32]) -> Self {
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort_by(|a, b| b.total_cmp(a));
+        Self {
+            thresholds,
+            alerted: None,
+            last_status: None,
+        }
+    }
+
+    /// Take a new battery status into account, returning what to alert about. Only the lowest
+    /// threshold crossed is alerted when the charge drops past several at once
+    pub fn update(&mut self, status: &BatteryStatus) -> Option<Alert> {
+        let last_status = self.last_status.replace(status.charging_status);
+
+        match status.charging_status {
+            ChargingStatus::Discharging => {
+                let charge = status.charge?;
+                let alerted = self.alerted;
+                let threshold = self.thresholds.iter().rev().cloned().find(|&threshold| {
+                    charge < threshold && alerted.map_or(true, |alerted| threshold < alerted)
+                })?;
+                self.alerted = Some(threshold);
+                Some(Alert::Low {
+                    threshold,
+                    charge,
+                    critical: self.thresholds.last() == Some(&threshold),
+                })
+            }
+            ChargingStatus::Unknown(_) => None,
+            charging_status => {
+                // Warn again on the next discharge
+                self.alerted = None;
+                match (last_status, charging_status) {
+                    (Some(last), ChargingStatus::Full) if last != ChargingStatus::Full => {
+                        Some(Alert::Charged)
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// Sends notifications to the desktop's notification server, each one replacing the last
+pub struct Notifier {
+    connection: Connection,
+    /// ID of the last notification sent
+    last_id: u32,
+}
+
+impl Notifier {
+    /// Connect to the session bus, as given by `DBUS_SESSION_BUS_ADDRESS`
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self::with_connection(Connection::new_session()?))
+    }
+
+    /// Send notifications over an existing connection
+    pub fn with_connection(connection: Connection) -> Self {
+        Self {
+            connection,
+            last_id: 0,
+        }
+    }
+
+    /// Notify the user about an alert
+    pub fn alert(&mut self, device_name: &str, alert: &Alert) -> Result<(), Error> {
+        match *alert {
+            Alert::Low {
+                charge,
+                critical: true,
+                ..
+            } => self.notify(
+                "battery-caution",
+                &format!("{} battery critical", device_name),
+                &format!("{:.0}% charge left", charge),
+                2,
+            ),
+            Alert::Low { charge, .. } => self.notify(
+                "battery-low",
+                &format!("{} battery low", device_name),
+                &format!("{:.0}% charge left", charge),
+                1,
+            ),
+            Alert::Charged => self.notify(
+                "battery-full-charged",
+                &format!("{} charged", device_name),
+                "The battery is full",
+                1,
+            ),
+        }
+    }
+
+    /// Send a notification with an icon name, summary, body and urgency (0 low, 1 normal,
+    /// 2 critical)
+    pub fn notify(
+        &mut self,
+        icon: &str,
+        summary: &str,
+        body: &str,
+        urgency: u8,
+    ) -> Result<(), Error> {
+        let proxy = self.connection.with_proxy(
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            TIMEOUT,
+        );
+
+        let mut hints = PropMap::new();
+        hints.insert(
+            "urgency".to_string(),
+            Variant(Box::new(urgency) as Box<dyn RefArg>),
+        );
+
+        let (id,): (u32,) = proxy.method_call(
+            "org.freedesktop.Notifications",
+            "Notify",
+            (
+                APP_NAME,
+                self.last_id,
+                icon,
+                summary,
+                body,
+                Vec::<&str>::new(),
+                hints,
+                -1i32,
+            ),
+        )?;
+
+        debug!("Sent notification {}: {}", id, summary);
+
+        self.last_id = id;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::channel::{MatchingReceiver, Sender};
+    use dbus::message::MatchRule;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    use test_bus::{self, TestBus};
+
+    /// A notification as the mock server got it
+    #[derive(Debug, PartialEq)]
+    struct Received {
+        replaces_id: u32,
+        icon: String,
+        summary: String,
+        urgency: u64,
+    }
+
+    fn status(charging_status: ChargingStatus, charge: f32) -> BatteryStatus {
+        BatteryStatus {
+            charging_status,
+            voltage: None,
+            charge: Some(charge),
+            uncertainty: Some(0.0),
+        }
+    }
+
+    /// Answer Notify calls on the bus until `running` is cleared, passing on what was received.
+    /// IDs are handed out from 1 up
+    fn mock_server(bus: &TestBus, running: Arc<AtomicBool>) -> mpsc::Receiver<Received> {
+        let (sender, receiver) = mpsc::channel();
+        let (ready, wait_ready) = mpsc::channel();
+        let address = bus.address().to_string();
+
+        thread::spawn(move || {
+            let connection = test_bus::connect(&address);
+            connection
+                .request_name("org.freedesktop.Notifications", false, true, true)
+                .unwrap();
+            let mut next_id = 1u32;
+            connection.start_receive(
+                MatchRule::new_method_call(),
+                Box::new(move |message, connection| {
+                    let mut args = message.iter_init();
+                    let _app_name: String = args.read().unwrap();
+                    let replaces_id: u32 = args.read().unwrap();
+                    let icon: String = args.read().unwrap();
+                    let summary: String = args.read().unwrap();
+                    let _body: String = args.read().unwrap();
+                    let _actions: Vec<String> = args.read().unwrap();
+                    let hints: PropMap = args.read().unwrap();
+                    sender
+                        .send(Received {
+                            replaces_id,
+                            icon,
+                            summary,
+                            urgency: hints["urgency"].0.as_u64().unwrap(),
+                        })
+                        .unwrap();
+                    connection
+                        .send(message.method_return().append1(next_id))
+                        .unwrap();
+                    next_id += 1;
+                    true
+                }),
+            );
+            ready.send(()).unwrap();
+            while running.load(Ordering::SeqCst) {
+                connection.process(Duration::from_millis(50)).unwrap();
+            }
+        });
+
+        wait_ready.recv().unwrap();
+        receiver
+    }
+
+    #[test]
+    fn alerts_once_per_threshold() {
+        let mut alerts = BatteryAlerts::new(&[10.0, 20.0]);
+        let discharging = |charge| status(ChargingStatus::Discharging, charge);
+
+        assert_eq!(alerts.update(&discharging(25.0)), None);
+        assert_eq!(
+            alerts.update(&discharging(19.0)),
+            Some(Alert::Low {
+                threshold: 20.0,
+                charge: 19.0,
+                critical: false,
+            })
+        );
+        assert_eq!(alerts.update(&discharging(18.0)), None);
+        // Dropping past both at once only alerts the lowest
+        let mut skipping = BatteryAlerts::new(&[10.0, 20.0]);
+        assert_eq!(
+            skipping.update(&discharging(5.0)),
+            Some(Alert::Low {
+                threshold: 10.0,
+                charge: 5.0,
+                critical: true,
+            })
+        );
+        assert_eq!(skipping.update(&discharging(4.0)), None);
+    }
+
+    #[test]
+    fn charging_resets_alerts() {
+        let mut alerts = BatteryAlerts::new(&[20.0]);
+        assert!(alerts
+            .update(&status(ChargingStatus::Discharging, 15.0))
+            .is_some());
+        assert_eq!(
+            alerts.update(&status(ChargingStatus::Charging(false), 16.0)),
+            None
+        );
+        assert!(alerts
+            .update(&status(ChargingStatus::Discharging, 15.0))
+            .is_some());
+    }
+
+    #[test]
+    fn charged_only_on_becoming_full() {
+        let mut alerts = BatteryAlerts::new(&[20.0]);
+        // Already full when we started watching
+        assert_eq!(alerts.update(&status(ChargingStatus::Full, 100.0)), None);
+        assert_eq!(
+            alerts.update(&status(ChargingStatus::Charging(true), 99.0)),
+            None
+        );
+        assert_eq!(
+            alerts.update(&status(ChargingStatus::Full, 100.0)),
+            Some(Alert::Charged)
+        );
+        assert_eq!(alerts.update(&status(ChargingStatus::Full, 100.0)), None);
+    }
+
+    #[test]
+    fn notifies_server_on_the_bus() {
+        let bus = TestBus::start();
+        let running = Arc::new(AtomicBool::new(true));
+        let received = mock_server(&bus, Arc::clone(&running));
+        let mut alerts = BatteryAlerts::new(&[20.0, 10.0, 5.0]);
+        let mut notifier = Notifier::with_connection(bus.connect());
+
+        let statuses = [
+            status(ChargingStatus::Discharging, 25.0),
+            status(ChargingStatus::Discharging, 19.0),
+            status(ChargingStatus::Discharging, 15.0),
+            status(ChargingStatus::Discharging, 9.0),
+            status(ChargingStatus::Discharging, 3.0),
+            status(ChargingStatus::Charging(false), 4.0),
+            status(ChargingStatus::Full, 100.0),
+        ];
+        for status in &statuses {
+            if let Some(alert) = alerts.update(status) {
+                notifier.alert("G933", &alert).unwrap();
+            }
+        }
+        running.store(false, Ordering::SeqCst);
+
+        let notification = |replaces_id, icon: &str, summary: &str, urgency| Received {
+            replaces_id,
+            icon: icon.to_string(),
+            summary: summary.to_string(),
+            urgency,
+        };
+        assert_eq!(
+            received.try_iter().collect::<Vec<_>>(),
+            vec![
+                notification(0, "battery-low", "G933 battery low", 1),
+                notification(1, "battery-low", "G933 battery low", 1),
+                notification(2, "battery-caution", "G933 battery critical", 2),
+                notification(3, "battery-full-charged", "G933 charged", 1),
+            ]
+        );
+    }
+}
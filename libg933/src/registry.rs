@@ -0,0 +1,200 @@
+//! Database of supported device models, so new models can be added without code changes
+//!
+//! Models are described in TOML (see `devices.toml` for the format). The built-in database is
+//! extended by `$XDG_DATA_HOME/g933-utils/devices.toml`, whose models replace built-in ones with
+//! the same name.
+
+use failure::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml;
+
+use lights::{Correction, Light, NO_CORRECTION};
+use xdg;
+use DeviceMatch;
+
+lazy_static! {
+    /// Built-in models, extended by the user's database
+    static ref MODELS: Vec<DeviceModel> = {
+        let mut models = parse(include_str!("devices.toml")).unwrap();
+
+        if let Some(path) = user_database_path() {
+            // A broken user database shouldn't keep the built-in devices from working
+            match read_database(&path) {
+                Ok(user_models) => merge(&mut models, user_models),
+                Err(error) => error!("Could not load device database: {}", error),
+            }
+        }
+
+        models
+    };
+
+    /// Every product ID of every model
+    static ref MATCHES: Vec<DeviceMatch> = {
+        let models: &'static [DeviceModel] = &MODELS;
+        let mut matches = Vec::new();
+        for model in models {
+            for &(pids, connection) in &[
+                (&model.wireless_pids, Connection::Wireless),
+                (&model.wired_pids, Connection::Wired),
+            ] {
+                matches.extend(pids.iter().map(|&pid| DeviceMatch {
+                    pid,
+                    model,
+                    connection,
+                }));
+            }
+        }
+        matches
+    };
+}
+
+/// How the headset is connected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connection {
+    /// Through its wireless receiver, running on battery
+    Wireless,
+    /// With a USB cable, with no battery to speak of
+    Wired,
+}
+
+/// Most equalizer bands that fit in a set_equalizer request, after the function and the
+/// permanence flag
+pub const MAX_EQUALIZER_BANDS: usize = 15;
+
+/// Layout of a device's equalizer
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EqualizerLayout {
+    /// Number of bands
+    pub bands: usize,
+    /// Lowest gain of a band
+    pub min: i8,
+    /// Highest gain of a band
+    pub max: i8,
+}
+
+/// Description of a device model
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceModel {
+    /// Name shown to the user
+    pub name: String,
+    /// USB product IDs of the wireless receiver
+    #[serde(default)]
+    pub wireless_pids: Vec<u16>,
+    /// USB product IDs of the headset plugged in with a cable
+    #[serde(default)]
+    pub wired_pids: Vec<u16>,
+    /// HID++ features the model is expected to have
+    #[serde(default)]
+    pub features: Vec<u16>,
+    /// Lighting zones
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    /// Equalizer layout, if the model has one
+    #[serde(default)]
+    pub equalizer: Option<EqualizerLayout>,
+    /// Battery curve set, as named by `battery::load_curves`
+    #[serde(default)]
+    pub curves: String,
+    /// Color correction for the LEDs, if they need it
+    #[serde(default)]
+    pub color_correction: Option<Correction>,
+}
+
+impl DeviceModel {
+    /// All of the model's product IDs, wireless first
+    pub fn pids<'a>(&'a self) -> impl Iterator<Item = u16> + 'a {
+        self.wireless_pids
+            .iter()
+            .chain(self.wired_pids.iter())
+            .cloned()
+    }
+
+    /// Color correction for the LEDs
+    pub fn correction(&self) -> Correction {
+        self.color_correction.unwrap_or(NO_CORRECTION)
+    }
+}
+
+/// The database file as a whole
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Database {
+    #[serde(default, rename = "device")]
+    devices: Vec<DeviceModel>,
+}
+
+/// Every known model
+pub fn models() -> &'static [DeviceModel] {
+    &MODELS
+}
+
+/// Find the model with a product ID. If more than one has it, the user's wins
+pub fn find(pid: u16) -> Option<&'static DeviceMatch> {
+    MATCHES.iter().rev().find(|dev| dev.pid == pid)
+}
+
+/// File that user models are loaded from automatically,
+/// `$XDG_DATA_HOME/g933-utils/devices.toml`
+pub fn user_database_path() -> Option<PathBuf> {
+    xdg::data_dir().map(|dir| dir.join("devices.toml"))
+}
+
+/// Read models from a database file, or none if it doesn't exist
+pub fn read_database(path: &Path) -> Result<Vec<DeviceModel>, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => bail!("{}: {}", path.display(), error),
+    };
+    let models = parse(&contents).map_err(|error| format_err!("{}: {}", path.display(), error))?;
+    info!("Loaded device database {}", path.display());
+    Ok(models)
+}
+
+/// Parse models from TOML, filling in the curve set if it is left out
+pub fn parse(input: &str) -> Result<Vec<DeviceModel>, Error> {
+    let mut models = toml::from_str::<Database>(input)?.devices;
+    for model in &mut models {
+        let pid = model
+            .pids()
+            .next()
+            .ok_or_else(|| format_err!("{} has no product IDs", model.name))?;
+        if model.curves.is_empty() {
+            model.curves = format!("{:04X}", pid);
+        }
+        if let Some(equalizer) = model.equalizer {
+            ensure!(
+                equalizer.bands <= MAX_EQUALIZER_BANDS,
+                "{} has {} equalizer bands, but at most {} are supported",
+                model.name,
+                equalizer.bands,
+                MAX_EQUALIZER_BANDS
+            );
+            ensure!(
+                equalizer.min <= equalizer.max,
+                "{} has an equalizer range from {} to {}",
+                model.name,
+                equalizer.min,
+                equalizer.max
+            );
+        }
+    }
+    Ok(models)
+}
+
+/// Add models to a list, replacing any with the same name
+fn merge(models: &mut Vec<DeviceModel>, extra: Vec<DeviceModel>) {
+    for model in extra {
+        match models
+            .iter_mut()
+            .find(|existing| existing.name == model.name)
+        {
+            Some(existing) => *existing = model,
+            None => models.push(model),
+        }
+    }
+}
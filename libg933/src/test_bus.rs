@@ -0,0 +1,55 @@
+//! A private bus for D-Bus tests, run with `dbus-daemon`
+
+use dbus::blocking::Connection;
+use dbus::channel::Channel;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+/// A bus of its own, stopped when dropped
+pub struct TestBus {
+    daemon: Child,
+    address: String,
+}
+
+impl TestBus {
+    /// Start a new bus
+    pub fn start() -> Self {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("dbus-daemon is needed for D-Bus tests");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.as_mut().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Self {
+            daemon,
+            address: address.trim().to_string(),
+        }
+    }
+
+    /// Address of the bus, for connecting from other threads
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Connect to the bus
+    pub fn connect(&self) -> Connection {
+        connect(&self.address)
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// Connect to a bus by address
+pub fn connect(address: &str) -> Connection {
+    let mut channel = Channel::open_private(address).unwrap();
+    channel.register().unwrap();
+    Connection::from(channel)
+}
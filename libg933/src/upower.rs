@@ -0,0 +1,389 @@
+//! Publish the battery on D-Bus in the shape of a UPower device, so desktop battery widgets can
+//! show it
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    PropertiesPropertiesChanged, RequestNameReply,
+};
+use dbus::blocking::Connection;
+use dbus::channel::Channel;
+use dbus::message::{MessageType, SignalArgs};
+use dbus::Path;
+use dbus_crossroads::Crossroads;
+use failure::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use battery::{BatteryStatus, ChargingStatus};
+
+/// Name the service is published under on the session bus
+pub const BUS_NAME: &str = "org.g933utils.UPower";
+
+/// Path of the published device
+pub const DEVICE_PATH: &str = "/org/g933utils/UPower/devices/headset";
+
+/// Interface the device is published with
+const INTERFACE: &str = "org.freedesktop.UPower.Device";
+
+/// UPower's device type for headsets
+const TYPE_HEADSET: u32 = 17;
+
+/// Battery state, numbered the way UPower does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /// Not known, or the headset is off
+    Unknown = 0,
+    /// Charging
+    Charging = 1,
+    /// Discharging
+    Discharging = 2,
+    /// Fully charged
+    FullyCharged = 4,
+    /// Plugged in but not charging
+    PendingCharge = 5,
+}
+
+impl From<ChargingStatus> for State {
+    fn from(charging_status: ChargingStatus) -> Self {
+        match charging_status {
+            ChargingStatus::Discharging => State::Discharging,
+            ChargingStatus::Charging(_) | ChargingStatus::SlowCharging => State::Charging,
+            ChargingStatus::Full => State::FullyCharged,
+            ChargingStatus::ChargingError => State::PendingCharge,
+            ChargingStatus::Unknown(_) => State::Unknown,
+        }
+    }
+}
+
+/// Icon name UPower would give a battery in a state with a charge percentage
+pub fn icon_name(state: State, percentage: Option<f64>) -> String {
+    let percentage = match (state, percentage) {
+        (State::Unknown, _) | (_, None) => return "battery-missing-symbolic".to_string(),
+        (State::FullyCharged, _) => return "battery-full-charged-symbolic".to_string(),
+        (_, Some(percentage)) => percentage,
+    };
+
+    let level = if percentage < 10.0 {
+        "caution"
+    } else if percentage < 30.0 {
+        "low"
+    } else if percentage < 60.0 {
+        "good"
+    } else {
+        "full"
+    };
+    let charging = match state {
+        State::Charging | State::PendingCharge => "-charging",
+        _ => "",
+    };
+    format!("battery-{}{}-symbolic", level, charging)
+}
+
+/// Properties of the published device
+#[derive(Debug, Clone, PartialEq)]
+struct Properties {
+    model: String,
+    serial: String,
+    is_present: bool,
+    percentage: f64,
+    state: State,
+    icon_name: String,
+    voltage: f64,
+    /// Seconds since the Unix epoch when anything else last changed
+    update_time: u64,
+}
+
+impl Default for Properties {
+    fn default() -> Self {
+        Self {
+            model: String::new(),
+            serial: String::new(),
+            is_present: false,
+            percentage: 0.0,
+            state: State::Unknown,
+            icon_name: icon_name(State::Unknown, None),
+            voltage: 0.0,
+            update_time: 0,
+        }
+    }
+}
+
+impl Properties {
+    /// The properties that can change, by name
+    fn to_map(&self) -> PropMap {
+        let mut map = PropMap::new();
+        {
+            let mut insert = |name: &str, value: Box<dyn RefArg>| {
+                map.insert(name.to_string(), Variant(value));
+            };
+            insert("Model", Box::new(self.model.clone()));
+            insert("Serial", Box::new(self.serial.clone()));
+            insert("IsPresent", Box::new(self.is_present));
+            insert("Percentage", Box::new(self.percentage));
+            insert("State", Box::new(self.state as u32));
+            insert("IconName", Box::new(self.icon_name.clone()));
+            insert("Voltage", Box::new(self.voltage));
+            insert("UpdateTime", Box::new(self.update_time));
+        }
+        map
+    }
+}
+
+/// A device published on the session bus, answering property requests from battery widgets
+pub struct BatteryService {
+    connection: Connection,
+    crossroads: Crossroads,
+    path: Path<'static>,
+}
+
+impl BatteryService {
+    /// Connect to the session bus, as given by `DBUS_SESSION_BUS_ADDRESS`, and publish the
+    /// device under `BUS_NAME`
+    pub fn new() -> Result<Self, Error> {
+        let connection = Connection::new_session()?;
+        match connection.request_name(BUS_NAME, false, true, true)? {
+            RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => (),
+            _ => bail!("{} is already taken on the session bus", BUS_NAME),
+        }
+        Ok(Self::with_connection(connection))
+    }
+
+    /// Publish the device over an existing connection, without asking for a bus name
+    pub fn with_connection(connection: Connection) -> Self {
+        let mut crossroads = Crossroads::new();
+        let interface = crossroads.register(INTERFACE, |b| {
+            b.property("NativePath")
+                .get(|_, _: &mut Properties| Ok(String::new()));
+            b.property("Vendor")
+                .get(|_, _: &mut Properties| Ok("Logitech".to_string()));
+            b.property("Model")
+                .get(|_, p: &mut Properties| Ok(p.model.clone()));
+            b.property("Serial")
+                .get(|_, p: &mut Properties| Ok(p.serial.clone()));
+            b.property("Type")
+                .get(|_, _: &mut Properties| Ok(TYPE_HEADSET));
+            b.property("PowerSupply")
+                .get(|_, _: &mut Properties| Ok(false));
+            b.property("IsRechargeable")
+                .get(|_, _: &mut Properties| Ok(true));
+            b.property("IsPresent")
+                .get(|_, p: &mut Properties| Ok(p.is_present));
+            b.property("Percentage")
+                .get(|_, p: &mut Properties| Ok(p.percentage));
+            b.property("State")
+                .get(|_, p: &mut Properties| Ok(p.state as u32));
+            b.property("IconName")
+                .get(|_, p: &mut Properties| Ok(p.icon_name.clone()));
+            b.property("Voltage")
+                .get(|_, p: &mut Properties| Ok(p.voltage));
+            b.property("UpdateTime")
+                .get(|_, p: &mut Properties| Ok(p.update_time));
+        });
+        let path = Path::from(DEVICE_PATH);
+        crossroads.insert(path.clone(), &[interface], Properties::default());
+
+        Self {
+            connection,
+            crossroads,
+            path,
+        }
+    }
+
+    /// Describe the headset the battery belongs to
+    pub fn set_device(&mut self, model: &str, serial: &str) -> Result<(), Error> {
+        self.update(|properties| {
+            properties.model = model.to_string();
+            properties.serial = serial.to_string();
+        })
+    }
+
+    /// Show a new battery status, or no battery at all if the headset is off or gone
+    pub fn set_battery(&mut self, status: Option<&BatteryStatus>) -> Result<(), Error> {
+        self.update(|properties| {
+            let state = status.map_or(State::Unknown, |status| status.charging_status.into());
+            let charge = status.and_then(|status| status.charge);
+            let voltage = status.and_then(|status| status.voltage);
+
+            properties.is_present = status.is_some();
+            properties.state = state;
+            properties.percentage = charge.map_or(0.0, |charge| f64::from(charge.round()));
+            properties.icon_name = icon_name(state, charge.map(f64::from));
+            properties.voltage = voltage.map_or(0.0, |voltage| f64::from(voltage) / 1000.0);
+        })
+    }
+
+    /// Answer requests from the bus until the timeout runs out
+    pub fn process(&mut self, timeout: Duration) -> Result<(), Error> {
+        let channel: &Channel = self.connection.channel();
+        channel
+            .read_write(Some(timeout))
+            .map_err(|_| format_err!("Lost the connection to the session bus"))?;
+        while let Some(message) = channel.pop_message() {
+            if message.msg_type() == MessageType::MethodCall {
+                let _ = self.crossroads.handle_message(message, &self.connection);
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the properties, letting listeners know if anything changed
+    fn update<F: FnOnce(&mut Properties)>(&mut self, change: F) -> Result<(), Error> {
+        let properties = self
+            .crossroads
+            .data_mut::<Properties>(&self.path)
+            .expect("the device should be published");
+        let old = properties.clone();
+        change(properties);
+        if *properties == old {
+            return Ok(());
+        }
+
+        properties.update_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+
+        let signal = PropertiesPropertiesChanged {
+            interface_name: INTERFACE.to_string(),
+            changed_properties: properties.to_map(),
+            invalidated_properties: Vec::new(),
+        };
+        self.connection
+            .channel()
+            .send(signal.to_emit_message(&self.path))
+            .map_err(|_| format_err!("Could not send a signal on the session bus"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties as DBusProperties;
+    use dbus::blocking::Proxy;
+    use dbus::Message;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    use test_bus::{self, TestBus};
+
+    fn status(charging_status: ChargingStatus, charge: f32) -> BatteryStatus {
+        BatteryStatus {
+            charging_status,
+            voltage: Some(3900),
+            charge: Some(charge),
+            uncertainty: Some(0.0),
+        }
+    }
+
+    #[test]
+    fn maps_charging_status_to_state() {
+        let state = |charging_status| State::from(charging_status);
+        assert_eq!(state(ChargingStatus::Discharging), State::Discharging);
+        assert_eq!(state(ChargingStatus::Charging(false)), State::Charging);
+        assert_eq!(state(ChargingStatus::SlowCharging), State::Charging);
+        assert_eq!(state(ChargingStatus::Full), State::FullyCharged);
+        assert_eq!(state(ChargingStatus::ChargingError), State::PendingCharge);
+        assert_eq!(state(ChargingStatus::Unknown(9)), State::Unknown);
+    }
+
+    #[test]
+    fn picks_icons_like_upower() {
+        assert_eq!(
+            icon_name(State::Discharging, Some(5.0)),
+            "battery-caution-symbolic"
+        );
+        assert_eq!(
+            icon_name(State::Discharging, Some(29.9)),
+            "battery-low-symbolic"
+        );
+        assert_eq!(
+            icon_name(State::Charging, Some(45.0)),
+            "battery-good-charging-symbolic"
+        );
+        assert_eq!(
+            icon_name(State::PendingCharge, Some(80.0)),
+            "battery-full-charging-symbolic"
+        );
+        assert_eq!(
+            icon_name(State::FullyCharged, Some(100.0)),
+            "battery-full-charged-symbolic"
+        );
+        assert_eq!(
+            icon_name(State::Discharging, None),
+            "battery-missing-symbolic"
+        );
+        assert_eq!(
+            icon_name(State::Unknown, Some(50.0)),
+            "battery-missing-symbolic"
+        );
+    }
+
+    #[test]
+    fn publishes_battery_on_the_bus() {
+        let bus = TestBus::start();
+        let mut service = BatteryService::with_connection(bus.connect());
+        let name = service.connection.unique_name().to_string();
+
+        let watcher = bus.connect();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        {
+            let changes = Arc::clone(&changes);
+            watcher
+                .add_match(
+                    PropertiesPropertiesChanged::match_rule(None, None),
+                    move |signal: PropertiesPropertiesChanged, _: &Connection, _: &Message| {
+                        let state = signal.changed_properties["State"].0.as_u64();
+                        changes.lock().unwrap().push(state);
+                        true
+                    },
+                )
+                .unwrap();
+        }
+
+        service
+            .set_battery(Some(&status(ChargingStatus::Discharging, 24.6)))
+            .unwrap();
+        // Nothing changed, so nothing to signal
+        service
+            .set_battery(Some(&status(ChargingStatus::Discharging, 24.6)))
+            .unwrap();
+        service
+            .set_battery(Some(&status(ChargingStatus::Charging(false), 25.0)))
+            .unwrap();
+        while watcher.process(Duration::from_millis(200)).unwrap() {}
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                Some(State::Discharging as u64),
+                Some(State::Charging as u64)
+            ]
+        );
+
+        let (sender, receiver) = mpsc::channel();
+        let address = bus.address().to_string();
+        thread::spawn(move || {
+            let reader = test_bus::connect(&address);
+            let proxy = Proxy::new(name, DEVICE_PATH, Duration::from_secs(5), &reader);
+            let percentage: f64 = proxy.get(INTERFACE, "Percentage").unwrap();
+            let state: u32 = proxy.get(INTERFACE, "State").unwrap();
+            let icon: String = proxy.get(INTERFACE, "IconName").unwrap();
+            let present: bool = proxy.get(INTERFACE, "IsPresent").unwrap();
+            sender.send((percentage, state, icon, present)).unwrap();
+        });
+        let properties = loop {
+            service.process(Duration::from_millis(50)).unwrap();
+            if let Ok(properties) = receiver.try_recv() {
+                break properties;
+            }
+        };
+        assert_eq!(
+            properties,
+            (
+                25.0,
+                State::Charging as u32,
+                "battery-low-charging-symbolic".to_string(),
+                true
+            )
+        );
+    }
+}
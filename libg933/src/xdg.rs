@@ -0,0 +1,30 @@
+//! Locations of per-user files, following the XDG base directory spec
+
+use std::env;
+use std::path::PathBuf;
+
+/// Name of our subdirectory in each base directory
+const APP_DIR: &str = "g933-utils";
+
+/// Directory for user data files, `$XDG_DATA_HOME/g933-utils`
+pub fn data_dir() -> Option<PathBuf> {
+    base_dir("XDG_DATA_HOME", ".local/share").map(|dir| dir.join(APP_DIR))
+}
+
+/// Directory for cached files, `$XDG_CACHE_HOME/g933-utils`
+pub fn cache_dir() -> Option<PathBuf> {
+    base_dir("XDG_CACHE_HOME", ".cache").map(|dir| dir.join(APP_DIR))
+}
+
+/// Get a base directory from its environment variable, falling back to a path in the home
+/// directory when it is unset or not absolute
+fn base_dir(variable: &str, fallback: &str) -> Option<PathBuf> {
+    env::var_os(variable)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| {
+            env::var_os("HOME")
+                .map(PathBuf::from)
+                .map(|home| home.join(fallback))
+        })
+}
@@ -2,6 +2,7 @@
 #![cfg_attr(feature = "cargo-clippy", allow(useless_format))]
 
 extern crate clap;
+extern crate ctrlc;
 extern crate env_logger;
 #[macro_use]
 extern crate failure;
@@ -11,24 +12,263 @@ extern crate libg933;
 #[macro_use]
 extern crate log;
 
-use clap::{App, SubCommand};
+use clap::{App, AppSettings, SubCommand};
 use failure::Error;
+use libg933::animation::{self, Animation, Easing};
+use libg933::audio::{self, Visualizer};
+use libg933::battery::{self, PowerEvent, Sample};
+use libg933::calibration;
+use libg933::health;
+use libg933::history::BatteryHistory;
+use libg933::lights::{self, Color, Effect, Light, ProfileType, Target};
+use libg933::logfile::{self, RotatingFile};
+#[cfg(feature = "dbus")]
+use libg933::notifications::{BatteryAlerts, Notifier};
+use libg933::registry::Connection;
+#[cfg(feature = "dbus")]
+use libg933::upower::BatteryService;
+use libg933::Device;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Parse an effect name and its parameters
+fn parse_effect(effect: &str, values: &[&str]) -> Result<Effect, Error> {
+    let color = || -> Result<Color, Error> {
+        values
+            .first()
+            .ok_or_else(|| format_err!("Missing color for effect: {}", effect))?
+            .parse()
+    };
+
+    let effect = match effect {
+        "off" => Effect::Off,
+        "static" => Effect::Static { color: color()? },
+        "breathing" => Effect::Breathing {
+            color: color()?,
+            period: Duration::try_from_secs_f32(values.get(1).unwrap_or(&"4").parse()?)?,
+            brightness: values.get(2).unwrap_or(&"100").parse()?,
+        },
+        "cycle" => Effect::ColorCycle {
+            period: Duration::try_from_secs_f32(values.first().unwrap_or(&"10").parse()?)?,
+            brightness: values.get(1).unwrap_or(&"100").parse()?,
+        },
+        e => bail!("Invalid effect: {}", e),
+    };
+    effect.validate()?;
+    Ok(effect)
+}
+
+/// Describe an effect for humans
+fn describe_effect(effect: &Effect) -> String {
+    match *effect {
+        Effect::Off => "off".to_string(),
+        Effect::Static { color } => format!("static {}", color),
+        Effect::Breathing {
+            color,
+            period,
+            brightness,
+        } => format!(
+            "breathing {} (every {:.1}s, {}% brightness)",
+            color,
+            period.as_secs_f32(),
+            brightness
+        ),
+        Effect::ColorCycle { period, brightness } => format!(
+            "color cycle (every {:.1}s, {}% brightness)",
+            period.as_secs_f32(),
+            brightness
+        ),
+    }
+}
+
+/// Format a duration for humans, like `7h 10m`
+fn format_duration(duration: Duration) -> String {
+    let minutes = (duration.as_secs() + 30) / 60;
+    if minutes < 60 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}h {}m", minutes / 60, minutes % 60)
+    }
+}
+
+/// Path of the battery log of a headset in the default location
+fn default_battery_log(serial: &str, json: bool) -> Result<PathBuf, Error> {
+    let extension = if json { "jsonl" } else { "csv" };
+    Ok(libg933::xdg::data_dir()
+        .ok_or_else(|| format_err!("Could not find the user data directory"))?
+        .join("logs")
+        .join(format!("battery-{}.{}", serial, extension)))
+}
+
+/// Follows the device a long-running mode works with, looking for it again whenever the
+/// receiver seems to have been unplugged
+struct DeviceTracker<'a> {
+    /// Device asked for by the user, if any
+    sysname: Option<&'a str>,
+    devices: HashMap<String, Device>,
+    /// Last error looking for devices, so it is only shown once while it keeps happening
+    find_error: Option<String>,
+}
+
+impl<'a> DeviceTracker<'a> {
+    fn new(sysname: Option<&'a str>) -> Self {
+        Self {
+            sysname,
+            devices: HashMap::new(),
+            find_error: None,
+        }
+    }
+
+    /// The device to work with, or `None` if it isn't connected right now. Wired headsets have
+    /// no battery to watch, so finding only those is an error
+    fn device(&mut self) -> Result<Option<&mut Device>, Error> {
+        if self.devices.is_empty() {
+            // The receiver may be half set up while it is being plugged in, so carry on looking
+            let devices = match libg933::find_devices() {
+                Ok(devices) => {
+                    self.find_error = None;
+                    devices
+                }
+                Err(error) => {
+                    let error = error.to_string();
+                    if self.find_error.as_ref() != Some(&error) {
+                        error!("{}", error);
+                        self.find_error = Some(error);
+                    }
+                    HashMap::new()
+                }
+            };
+            let (wireless, wired): (HashMap<_, _>, HashMap<_, _>) = devices
+                .into_iter()
+                .partition(|(_, device)| device.has_battery());
+            match self.sysname {
+                Some(sysname) => ensure!(
+                    !wired.contains_key(sysname),
+                    "{} is wired, so it has no battery to watch",
+                    sysname
+                ),
+                None => ensure!(
+                    !wireless.is_empty() || wired.is_empty(),
+                    "Only wired headsets were found, which have no battery to watch"
+                ),
+            }
+            self.devices = wireless;
+        }
+        let found = match self.sysname {
+            Some(sysname) => self.devices.contains_key(sysname),
+            None => !self.devices.is_empty(),
+        };
+        if !found {
+            info!("Device not found");
+            self.devices.clear();
+            return Ok(None);
+        }
+
+        Ok(match self.sysname {
+            Some(sysname) => self.devices.get_mut(sysname),
+            None => self.devices.values_mut().next(),
+        })
+    }
+
+    /// Deal with a failed battery request. Requests just time out while the headset is off,
+    /// anything else means the receiver has gone away and has to be looked for again
+    fn failed(&mut self, error: &Error) {
+        info!("Could not read battery: {}", error);
+        if error.downcast_ref::<io::Error>().is_some() {
+            self.devices.clear();
+        }
+    }
+}
+
+/// Get a flag that is cleared when the user interrupts us with Ctrl-C
+fn interrupt_flag() -> Result<Arc<AtomicBool>, Error> {
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_running = Arc::clone(&running);
+    ctrlc::set_handler(move || handler_running.store(false, Ordering::SeqCst))?;
+    Ok(running)
+}
+
+/// Subcommands that talk to the desktop over D-Bus
+#[cfg(feature = "dbus")]
+fn dbus_subcommands() -> Vec<App<'static, 'static>> {
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    let subcommands = vec![
+        SubCommand::with_name("notify")
+            .about("Send desktop notifications about the battery until interrupted")
+            .args_from_usage("
+                -d, --device [device]         'Device to watch'
+                -i, --interval [interval]     'Seconds between battery checks (default 60)'
+                -t, --thresholds [thresholds] 'Charge percentages to warn at (default 20,10,5)'
+            ")
+            .after_help(indoc!("
+                A notification is sent through org.freedesktop.Notifications on the session bus
+                when the charge drops below each threshold, and when charging completes. The
+                battery is checked every interval, and whenever the headset reports a change.
+
+                Notifications go to the bus in DBUS_SESSION_BUS_ADDRESS, so they can be tried
+                out on a private bus started with dbus-run-session, with a mock notification
+                server listening on it.
+            ")),
+        SubCommand::with_name("upower")
+            .about("Publish the battery as a UPower device on the session bus until interrupted")
+            .args_from_usage("
+                -d, --device [device]     'Device to publish'
+                -i, --interval [interval] 'Seconds between battery checks (default 60)'
+            ")
+            .after_help(indoc!("
+                The headset is published as an org.freedesktop.UPower.Device at
+                /org/g933utils/UPower/devices/headset under the bus name org.g933utils.UPower,
+                with its Percentage, State, Model, Serial and IconName kept up to date from
+                battery checks and the changes the headset reports. IsPresent is false while the
+                headset is off.
+            ")),
+    ];
+    subcommands
+}
+
+/// Subcommands that talk to the desktop over D-Bus, which this build has no support for
+#[cfg(not(feature = "dbus"))]
+fn dbus_subcommands() -> Vec<App<'static, 'static>> {
+    Vec::new()
+}
 
 fn run() -> Result<(), Error> {
     #[cfg_attr(rustfmt, rustfmt_skip)]
     let matches = App::new("g933control")
         .author("Ash Lea <ashlea@protonmail.com>")
         .about("Configure and control the Logitech G933 Gaming Headset")
+        .args_from_usage("
+            --curves-dir [curves-dir] 'Directory to load extra battery curves from'
+        ")
         .subcommand(SubCommand::with_name("list")
             .about("List attached devices")
         )
         .after_help(indoc!("
             Use --help with any subcommand for more information
+
+            Battery curves are also loaded from $XDG_DATA_HOME/g933-utils/curves. Either
+            directory holds a subdirectory per curve set, named after the product ID of the
+            device that uses it (like 0A5B) unless the device database says otherwise,
+            containing any of discharging.csv, charging_ascending.csv and
+            charging_descending.csv with `voltage,charge` lines. These override the built-in
+            curves.
+
+            Supported devices are described in a built-in database, which can be extended with
+            $XDG_DATA_HOME/g933-utils/devices.toml in the same format. Models there replace
+            built-in ones with the same name.
         "))
         .subcommand(SubCommand::with_name("get")
             .about("Get a property of a device")
             .args_from_usage("
                 -d, --device [device] 'Device to get property from'
+                --health              'Also estimate battery health from the battery log'
+                -l, --log [log]       'Battery log to estimate health from'
                 <property>            'Property to get'
             ")
             .after_help(indoc!("
@@ -41,6 +281,10 @@ fn run() -> Result<(), Error> {
                     poweroff_timeout
                     sidetone_volume
                     startup_effect
+
+                Battery health compares how long the battery lasted in the latest discharge in
+                the log written by `log battery` to the first one. It needs discharges covering
+                at least half of the charge.
             "))
         )
         .subcommand(SubCommand::with_name("set")
@@ -53,7 +297,7 @@ fn run() -> Result<(), Error> {
             .after_help(indoc!("
                 Valid options for `property` are:
                     buttons (bool)
-                    equalizer (-12 - 11...)
+                    equalizer (-12 - 12...)
                     poweroff_timeout (1 - 255 or 'never')
                     sidetone_volume (0 - 100)
                     startup_effect (bool)
@@ -67,7 +311,201 @@ fn run() -> Result<(), Error> {
             ")
             .after_help(indoc!("
                 Valid options for `event` are:
+                    battery (battery status reported by the headset)
                     buttons
+                    lighting_sync
+                    power (charger plugged in or out, headset turning off)
+            "))
+        )
+        .subcommand(SubCommand::with_name("lights")
+            .about("Get or set light effects")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("get")
+                .about("Get the current effect of each light")
+                .args_from_usage("
+                    -d, --device [device] 'Device to get lights from'
+                    -s, --stored          'Get the effects stored as the power-on default instead'
+                ")
+            )
+            .subcommand(SubCommand::with_name("set")
+                .about("Set the effect of a light")
+                .args_from_usage("
+                    -d, --device [device]         'Device to set lights on'
+                    -t, --transition [transition] 'Milliseconds to fade from the current effect'
+                    -e, --easing [easing]         'Easing of the fade (default ease-in-out)'
+                    <light>                       'Light to set'
+                    <effect>                      'Effect to show'
+                    [values]...                   'Parameters of the effect'
+                ")
+                .after_help(indoc!("
+                    Valid options for `light` are:
+                        logo
+                        side
+
+                    Valid options for `effect` are:
+                        off
+                        static (color)
+                        breathing (color, seconds per breath, brightness %)
+                        cycle (seconds per cycle, brightness %)
+
+                    Valid options for `easing` are:
+                        linear
+                        ease-in
+                        ease-out
+                        ease-in-out
+
+                    Colors can be given as #rrggbb, #rgb, CSS color names, hsv(h, s%, v%),
+                    hsl(h, s%, l%) or color temperatures like 6500K
+                "))
+            )
+            .subcommand(SubCommand::with_name("save")
+                .about("Store the effect of a light onboard as the power-on default")
+                .args_from_usage("
+                    -d, --device [device] 'Device to save lights on'
+                    -a, --apply           'Also show the effect right away'
+                    <light>               'Light to save'
+                    <effect>              'Effect to save'
+                    [values]...           'Parameters of the effect'
+                ")
+                .after_help(indoc!("
+                    Valid options for `light` are:
+                        logo
+                        side
+
+                    Valid options for `effect` are:
+                        off
+                        static (color)
+                        breathing (color, seconds per breath, brightness %)
+                        cycle (seconds per cycle, brightness %)
+
+                    Colors can be given as #rrggbb, #rgb, CSS color names, hsv(h, s%, v%),
+                    hsl(h, s%, l%) or color temperatures like 6500K
+                "))
+            )
+        )
+        .subcommand(SubCommand::with_name("flash")
+            .about("Blink the lights in a color, then put back the previous effects")
+            .args_from_usage("
+                -d, --device [device] 'Device to flash'
+                -l, --light [light]   'Light to flash (default all)'
+                -t, --times [times]   'Number of times to blink (default 3)'
+                -p, --period [period] 'Milliseconds per blink (default 500)'
+                <color>               'Color to blink in'
+            ")
+            .after_help(indoc!("
+                Valid options for `light` are:
+                    logo
+                    side
+
+                Colors can be given as #rrggbb, #rgb, CSS color names, hsv(h, s%, v%),
+                hsl(h, s%, l%) or color temperatures like 6500K
+            "))
+        )
+        .subcommand(SubCommand::with_name("animate")
+            .about("Play a lighting animation until interrupted")
+            .args_from_usage("
+                -d, --device [device] 'Device to animate'
+                -p, --period [period] 'Length of one animation cycle in milliseconds'
+                <animation>           'Animation to play'
+                [colors]...           'Colors to use'
+            ")
+            .after_help(indoc!("
+                Valid options for `animation` are:
+                    rainbow
+                    alternate (2 colors)
+                    strobe (1 color)
+                    fade (2 colors)
+
+                Colors can be given as #rrggbb, #rgb, CSS color names, hsv(h, s%, v%),
+                hsl(h, s%, l%) or color temperatures like 6500K
+            "))
+        )
+        .subcommand(SubCommand::with_name("visualize")
+            .about("Drive the lights from audio until interrupted or the input ends")
+            .args_from_usage("
+                -d, --device [device]     'Device to light up'
+                -r, --rate [rate]         'Sample rate of raw input (default 44100)'
+                -c, --channels [channels] 'Number of channels in raw input (default 2)'
+                [file]                    'WAV file to read instead of raw input on stdin'
+            ")
+            .after_help(indoc!("
+                Raw input must be signed 16 bit little endian PCM, for example:
+                    parec --format=s16le | g933-utils visualize
+            "))
+        )
+        .subcommand(SubCommand::with_name("indicator")
+            .about("Show the battery level on the side lights until interrupted")
+            .args_from_usage("
+                -d, --device [device]     'Device to show battery level on'
+                -i, --interval [interval] 'Seconds between battery checks (default 60)'
+            ")
+            .after_help(indoc!("
+                The side lights go from green when full to red when empty, and breathe while
+                charging. They are put back the way they were when interrupted.
+            "))
+        )
+        .subcommand(SubCommand::with_name("calibrate")
+            .about("Record a charge and discharge cycle and fit battery curves from it")
+            .args_from_usage("
+                -d, --device [device]     'Device to calibrate'
+                -i, --interval [interval] 'Seconds between samples (default 60)'
+                -s, --samples [samples]   'File to record samples to (default calibration.csv)'
+                -o, --output [output]     'Directory to write curves to'
+                -f, --fit                 'Fit curves from recorded samples without recording more'
+            ")
+            .after_help(indoc!("
+                Start with the headset fully charged and unplugged, and leave it running until it
+                turns itself off. Then plug it in and leave it charging until it is full. Samples
+                are added to the end of the samples file as they are taken, so recording can be
+                stopped and picked up again later. When interrupted, curves are fitted from all
+                of the samples in the file.
+
+                Curves are written to $XDG_DATA_HOME/g933-utils/curves/<curve set> by default,
+                where they are picked up automatically. With --fit and --output, the headset
+                doesn't need to be connected.
+            "))
+        )
+        .subcommand(SubCommand::with_name("log")
+            .about("Log readings to a file until interrupted")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("battery")
+                .about("Log battery voltage, status and charge")
+                .args_from_usage("
+                    -d, --device [device]     'Device to log'
+                    -i, --interval [interval] 'Seconds between samples (default 60)'
+                    -o, --output [output]     'File to log to'
+                    -f, --format [format]     'Format of the log (default csv)'
+                    -s, --max-size [max-size] 'Kilobytes a log file can grow to (default 1024)'
+                    -k, --keep [keep]         'Number of old log files to keep (default 5)'
+                ")
+                .after_help(indoc!("
+                    Valid options for `format` are:
+                        csv (time, voltage, status, charge)
+                        json (one object per line)
+
+                    Logs go to $XDG_DATA_HOME/g933-utils/logs/battery-<serial>.csv or .jsonl by
+                    default, so each headset gets its own. Once a log is full it is moved to
+                    battery-<serial>.csv.1 and so on. CSV logs can be fed straight to
+                    `calibrate --fit --samples`.
+
+                    Samples are skipped while the headset is off, and logging picks up again
+                    when it comes back.
+                "))
+            )
+        )
+        .subcommands(dbus_subcommands())
+        .subcommand(SubCommand::with_name("sync")
+            .about("Sync the light effects of all devices to one of them")
+            .args_from_usage("
+                -d, --device [device] 'Device to sync the others to'
+                [light]               'Light to sync (default all)'
+            ")
+            .after_help(indoc!("
+                Valid options for `light` are:
+                    logo
+                    side
+
+                Only breathing and color cycle effects can be synced.
             "))
         )
         .subcommand(SubCommand::with_name("raw")
@@ -83,9 +521,22 @@ fn run() -> Result<(), Error> {
         )
         .get_matches();
 
+    if let Some(dir) = matches.value_of("curves-dir") {
+        battery::load_curves(Path::new(dir))?;
+    }
+
     if matches.subcommand_matches("list").is_some() {
         for (sysname, mut device) in libg933::find_devices()? {
-            println!("Device {}: {}", sysname, device.get_device_name()?);
+            let connection = match device.connection() {
+                Connection::Wireless => "wireless",
+                Connection::Wired => "wired",
+            };
+            println!(
+                "Device {}: {} ({})",
+                sysname,
+                device.get_device_name()?,
+                connection
+            );
         }
     }
 
@@ -106,18 +557,109 @@ fn run() -> Result<(), Error> {
             "battery" => {
                 use libg933::battery::ChargingStatus::*;
 
+                // Keep a history between runs so we can tell how quickly the charge is changing,
+                // and whether charging has reached the descending phase. It's only a cache, so
+                // carry on without it if it can't be used
+                let history_path = match device.serial() {
+                    Ok(serial) => BatteryHistory::default_path(&serial),
+                    Err(error) => {
+                        warn!("Could not read the serial number: {}", error);
+                        None
+                    }
+                };
+                let mut history = match history_path {
+                    Some(ref path) => BatteryHistory::load(path).unwrap_or_else(|error| {
+                        warn!("Could not load the battery history: {}", error);
+                        BatteryHistory::new()
+                    }),
+                    None => BatteryHistory::new(),
+                };
+                device.set_charge_phase(history.charge_phase());
+
                 let battery_status = device.get_battery_status()?;
                 let charging_status = match battery_status.charging_status {
-                    Discharging => "discharging",
-                    Charging(false) => "charging (ascending)",
-                    Charging(true) => "charging (descending)",
-                    Full => "full",
+                    Discharging => "discharging".to_string(),
+                    Charging(false) => "charging (ascending)".to_string(),
+                    Charging(true) => "charging (descending)".to_string(),
+                    SlowCharging => "charging slowly".to_string(),
+                    Full => "full".to_string(),
+                    ChargingError => "charging error".to_string(),
+                    Unknown(code) => format!("unknown status {}", code),
                 };
 
-                println!(
-                    "Status: {:.01}% [{}]",
-                    battery_status.charge, charging_status
+                let sample = Sample::now(
+                    battery_status.charging_status,
+                    battery_status.voltage,
+                    battery_status.charge,
                 );
+                history.push(sample);
+                if let Some(ref path) = history_path {
+                    if let Err(error) = history.save(path) {
+                        warn!("Could not save the battery history: {}", error);
+                    }
+                }
+
+                let remaining = match history.estimate() {
+                    Some(estimate) => {
+                        let until = match estimate.charging_status {
+                            Discharging => "left",
+                            _ => "until full",
+                        };
+                        format!(" – about {} {}", format_duration(estimate.remaining), until)
+                    }
+                    None => String::new(),
+                };
+
+                match (battery_status.charge, battery_status.uncertainty) {
+                    (Some(charge), Some(uncertainty)) => println!(
+                        "Status: {:.01}% (±{:.01}) [{}]{}",
+                        charge, uncertainty, charging_status, remaining
+                    ),
+                    _ => match battery_status.voltage {
+                        Some(voltage) => println!("Status: {} mV [{}]", voltage, charging_status),
+                        None => println!("Status: unknown [{}]", charging_status),
+                    },
+                }
+
+                if matches.is_present("health") {
+                    // Either format can be logged by default, so read both
+                    let logs = match matches.value_of("log") {
+                        Some(log) => vec![PathBuf::from(log)],
+                        None => {
+                            let serial = device.serial()?;
+                            vec![
+                                default_battery_log(&serial, false)?,
+                                default_battery_log(&serial, true)?,
+                            ]
+                        }
+                    };
+                    let mut samples = Vec::new();
+                    for path in logs.iter().flat_map(|log| logfile::existing_files(log)) {
+                        samples.extend(
+                            battery::read_samples(BufReader::new(File::open(&path)?))
+                                .map_err(|error| format_err!("{}: {}", path.display(), error))?,
+                        );
+                    }
+                    samples.sort_by_key(|sample| sample.time);
+
+                    match health::estimate(&samples) {
+                        Some(health) => {
+                            println!(
+                                "Health: {:.0}% ({} per full discharge, {} at first, {} cycles)",
+                                health.percentage,
+                                format_duration(health.latest.runtime),
+                                format_duration(health.first.runtime),
+                                health.cycles
+                            );
+                            if let (Some(latest), Some(first)) =
+                                (health.latest.sag, health.first.sag)
+                            {
+                                println!("Voltage sag: {} mV ({} mV at first)", latest, first);
+                            }
+                        }
+                        None => println!("Health: unknown, no full enough discharges logged"),
+                    }
+                }
             }
             "button_count" => {
                 println!("Number of buttons: {}", device.get_button_count()?);
@@ -178,16 +720,30 @@ fn run() -> Result<(), Error> {
                 device.enable_buttons(enable)?;
             }
             "equalizer" => {
-                ensure!(values.len() <= 10, "Only 10 equalizer values allowed");
+                let layout = device
+                    .model()
+                    .equalizer
+                    .ok_or_else(|| format_err!("{} has no equalizer", device.model().name))?;
+                ensure!(
+                    values.len() <= layout.bands,
+                    "Only {} equalizer values allowed",
+                    layout.bands
+                );
 
                 // Start with the old config and overwrite parts
                 let mut config = device.get_equalizer()?;
                 for (i, value) in values.iter().enumerate() {
                     config[i] = i8::from_str_radix(value, 16).unwrap();
+                    ensure!(
+                        layout.min <= config[i] && config[i] <= layout.max,
+                        "Equalizer values must be from {} to {}",
+                        layout.min,
+                        layout.max
+                    );
                 }
 
-                device.set_equalizer(false, config)?;
-                device.set_equalizer(true, config)?;
+                device.set_equalizer(false, &config)?;
+                device.set_equalizer(true, &config)?;
             }
             "poweroff_timeout" => {
                 let timeout = match values[0] {
@@ -233,10 +789,572 @@ fn run() -> Result<(), Error> {
                     println!("g1: {}, g2: {}, g3: {}", buttons.g1, buttons.g2, buttons.g3);
                 })?;
             }
+            "lighting_sync" => {
+                device.watch_lighting_sync(|event| {
+                    println!("{:?} light cycle (rate {})", event.light, event.rate);
+                })?;
+            }
+            "battery" => {
+                device.watch_power(|event| {
+                    if let PowerEvent::StatusChanged(status) = event {
+                        let charge = match status.charge {
+                            Some(charge) => format!("{:.01}% ", charge),
+                            None => String::new(),
+                        };
+                        let voltage = match status.voltage {
+                            Some(voltage) => format!(" {} mV", voltage),
+                            None => String::new(),
+                        };
+                        println!("{}[{}]{}", charge, status.charging_status, voltage);
+                    }
+                })?;
+            }
+            "power" => {
+                device.watch_power(|event| match event {
+                    PowerEvent::ChargerConnected => println!("Charger connected"),
+                    PowerEvent::ChargerDisconnected => println!("Charger disconnected"),
+                    PowerEvent::PoweredOff => println!("Powered off"),
+                    PowerEvent::StatusChanged(_) => (),
+                })?;
+            }
             e => println!("Invalid event: {}", e),
         }
     }
 
+    if let Some(matches) = matches.subcommand_matches("lights") {
+        if let Some(matches) = matches.subcommand_matches("get") {
+            let mut devices = libg933::find_devices()?;
+            let device = match matches.value_of("device") {
+                Some(sysname) => devices
+                    .get_mut(sysname)
+                    .ok_or_else(|| format_err!("No such device: {}", sysname))?,
+                None => devices
+                    .values_mut()
+                    .next()
+                    .ok_or_else(|| format_err!("No devices found"))?,
+            };
+
+            for light in &device.model().lights {
+                let config = if matches.is_present("stored") {
+                    device.get_stored_lights(*light)?
+                } else {
+                    device.get_lights(*light)?
+                };
+                println!("{:?}: {}", light, describe_effect(&config.effect));
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("set") {
+            let light = matches.value_of("light").unwrap().parse::<Light>()?;
+            let values: Vec<&str> = matches
+                .values_of("values")
+                .map(|values| values.collect())
+                .unwrap_or_default();
+            let effect = parse_effect(matches.value_of("effect").unwrap(), &values)?;
+            let mut devices = libg933::find_devices()?;
+            let device = match matches.value_of("device") {
+                Some(sysname) => devices
+                    .get_mut(sysname)
+                    .ok_or_else(|| format_err!("No such device: {}", sysname))?,
+                None => devices
+                    .values_mut()
+                    .next()
+                    .ok_or_else(|| format_err!("No devices found"))?,
+            };
+
+            let config = lights::Config {
+                light,
+                effect: device.correct_effect(effect),
+                profile_type: ProfileType::Temporary,
+            };
+            match matches.value_of("transition") {
+                Some(transition) => {
+                    let duration = Duration::from_millis(transition.parse()?);
+                    let easing = matches
+                        .value_of("easing")
+                        .unwrap_or("ease-in-out")
+                        .parse::<Easing>()?;
+                    let current = device.get_lights(light)?;
+                    device.transition_lights(&current, &config, duration, easing)?;
+                }
+                None => {
+                    device.set_lights(&config)?;
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("save") {
+            let light = matches.value_of("light").unwrap().parse::<Light>()?;
+            let values: Vec<&str> = matches
+                .values_of("values")
+                .map(|values| values.collect())
+                .unwrap_or_default();
+            let effect = parse_effect(matches.value_of("effect").unwrap(), &values)?;
+            let target = if matches.is_present("apply") {
+                Target::Both
+            } else {
+                Target::PowerOn
+            };
+            let mut devices = libg933::find_devices()?;
+            let device = match matches.value_of("device") {
+                Some(sysname) => devices
+                    .get_mut(sysname)
+                    .ok_or_else(|| format_err!("No such device: {}", sysname))?,
+                None => devices
+                    .values_mut()
+                    .next()
+                    .ok_or_else(|| format_err!("No devices found"))?,
+            };
+
+            let effect = device.correct_effect(effect);
+            device.store_lights(light, effect, target)?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("flash") {
+        let light = match matches.value_of("light") {
+            Some(light) => Some(light.parse::<Light>()?),
+            None => None,
+        };
+        let color = matches.value_of("color").unwrap().parse::<Color>()?;
+        let times = matches.value_of("times").unwrap_or("3").parse()?;
+        let period = Duration::from_millis(matches.value_of("period").unwrap_or("500").parse()?);
+        let mut devices = libg933::find_devices()?;
+        let device = match matches.value_of("device") {
+            Some(sysname) => devices
+                .get_mut(sysname)
+                .ok_or_else(|| format_err!("No such device: {}", sysname))?,
+            None => devices
+                .values_mut()
+                .next()
+                .ok_or_else(|| format_err!("No devices found"))?,
+        };
+
+        let lights = match light {
+            Some(light) => vec![light],
+            None => device.model().lights.clone(),
+        };
+        let color = device.correct_color(color);
+        device.flash_lights(&lights, color, times, period)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("animate") {
+        let name = matches.value_of("animation").unwrap();
+        let period = Duration::from_millis(matches.value_of("period").unwrap_or("2000").parse()?);
+        let colors = matches
+            .values_of("colors")
+            .map(|colors| colors.map(str::parse).collect::<Result<Vec<Color>, _>>())
+            .unwrap_or_else(|| Ok(Vec::new()))?;
+        let mut devices = libg933::find_devices()?;
+        let device = match matches.value_of("device") {
+            Some(sysname) => devices
+                .get_mut(sysname)
+                .ok_or_else(|| format_err!("No such device: {}", sysname))?,
+            None => devices
+                .values_mut()
+                .next()
+                .ok_or_else(|| format_err!("No devices found"))?,
+        };
+
+        let mut animation: Box<dyn Animation> = match (name, colors.len()) {
+            ("rainbow", 0) => Box::new(animation::Rainbow { period }),
+            ("alternate", 2) => Box::new(animation::Alternate {
+                colors: [colors[0], colors[1]],
+                period,
+            }),
+            ("strobe", 1) => Box::new(animation::Strobe {
+                color: colors[0],
+                period,
+            }),
+            ("fade", 2) => Box::new(animation::Fade {
+                from: colors[0],
+                to: colors[1],
+                period,
+            }),
+            ("rainbow", _) | ("alternate", _) | ("strobe", _) | ("fade", _) => {
+                bail!("Wrong number of colors for animation: {}", name)
+            }
+            (a, _) => bail!("Invalid animation: {}", a),
+        };
+
+        let running = interrupt_flag()?;
+        animation::run(device, &mut *animation, animation::FRAME_TIME, &running)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("visualize") {
+        let mut devices = libg933::find_devices()?;
+        let device = match matches.value_of("device") {
+            Some(sysname) => devices
+                .get_mut(sysname)
+                .ok_or_else(|| format_err!("No such device: {}", sysname))?,
+            None => devices
+                .values_mut()
+                .next()
+                .ok_or_else(|| format_err!("No devices found"))?,
+        };
+
+        let mut visualizer: Box<dyn Animation> = match matches.value_of("file") {
+            Some(path) => Box::new(Visualizer::wav(BufReader::new(File::open(path)?))?),
+            None => {
+                let format = audio::Format {
+                    sample_rate: matches.value_of("rate").unwrap_or("44100").parse()?,
+                    channels: matches.value_of("channels").unwrap_or("2").parse()?,
+                };
+                ensure!(
+                    format.sample_rate > 0 && format.channels > 0,
+                    "Sample rate and channels must be greater than 0"
+                );
+                Box::new(Visualizer::raw(BufReader::new(io::stdin()), format))
+            }
+        };
+
+        let running = interrupt_flag()?;
+        animation::run(device, &mut *visualizer, animation::FRAME_TIME, &running)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("indicator") {
+        let interval = Duration::from_secs(matches.value_of("interval").unwrap_or("60").parse()?);
+        let mut devices = libg933::find_devices()?;
+        let device = match matches.value_of("device") {
+            Some(sysname) => devices
+                .get_mut(sysname)
+                .ok_or_else(|| format_err!("No such device: {}", sysname))?,
+            None => devices
+                .values_mut()
+                .next()
+                .ok_or_else(|| format_err!("No devices found"))?,
+        };
+
+        let running = interrupt_flag()?;
+        libg933::indicator::run(device, interval, &running)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("calibrate") {
+        let interval = Duration::from_secs(matches.value_of("interval").unwrap_or("60").parse()?);
+        let samples_path = Path::new(matches.value_of("samples").unwrap_or("calibration.csv"));
+        let fit_only = matches.is_present("fit");
+        let mut output = matches.value_of("output").map(PathBuf::from);
+
+        // Fitting recorded samples into a given directory doesn't need the headset
+        if !fit_only || output.is_none() {
+            let mut devices = libg933::find_devices()?;
+            let device = match matches.value_of("device") {
+                Some(sysname) => devices
+                    .get_mut(sysname)
+                    .ok_or_else(|| format_err!("No such device: {}", sysname))?,
+                None => devices
+                    .values_mut()
+                    .next()
+                    .ok_or_else(|| format_err!("No devices found"))?,
+            };
+
+            if output.is_none() {
+                output = Some(
+                    battery::user_curves_dir()
+                        .ok_or_else(|| format_err!("Could not find the user data directory"))?
+                        .join(&device.model().curves),
+                );
+            }
+
+            if !fit_only {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(samples_path)?;
+                let running = interrupt_flag()?;
+                let mut last_sample: Option<Instant> = None;
+
+                println!("Recording to {}, interrupt to stop", samples_path.display());
+                while running.load(Ordering::SeqCst) {
+                    if last_sample.map_or(true, |time| time.elapsed() >= interval) {
+                        last_sample = Some(Instant::now());
+                        // The headset turns off once it runs flat, so keep trying until it's back
+                        match device.get_battery_sample() {
+                            Ok(sample) => {
+                                let voltage = match sample.voltage {
+                                    Some(voltage) => voltage,
+                                    None => {
+                                        bail!("The headset doesn't report its battery voltage")
+                                    }
+                                };
+                                writeln!(file, "{}", sample)?;
+                                println!("{} mV [{}]", voltage, sample.charging_status);
+                            }
+                            Err(error) => println!("Could not read battery: {}", error),
+                        }
+                    }
+
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+
+        let samples = battery::read_samples(BufReader::new(File::open(samples_path)?))
+            .map_err(|error| format_err!("{}: {}", samples_path.display(), error))?;
+        let curves = calibration::fit(&samples);
+        ensure!(
+            !curves.is_empty(),
+            "Not enough samples to fit any curves, record a full charge or discharge first"
+        );
+
+        let output = output.expect("the output directory should be known by now");
+        for path in calibration::write_curves(&output, &curves)? {
+            println!("Wrote {}", path.display());
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("log") {
+        if let Some(matches) = matches.subcommand_matches("battery") {
+            let interval =
+                Duration::from_secs(matches.value_of("interval").unwrap_or("60").parse()?);
+            let json = match matches.value_of("format").unwrap_or("csv") {
+                "csv" => false,
+                "json" => true,
+                f => bail!("Invalid format: {}", f),
+            };
+            let output = matches.value_of("output").map(PathBuf::from);
+            let max_size = matches
+                .value_of("max-size")
+                .unwrap_or("1024")
+                .parse::<u64>()?
+                * 1024;
+            let keep = matches.value_of("keep").unwrap_or("5").parse()?;
+            // Opened once the serial of the headset is known, for the default path
+            let mut log: Option<RotatingFile> = None;
+
+            let running = interrupt_flag()?;
+            let mut tracker = DeviceTracker::new(matches.value_of("device"));
+            let mut last_sample: Option<Instant> = None;
+
+            while running.load(Ordering::SeqCst) {
+                if last_sample.map_or(true, |time| time.elapsed() >= interval) {
+                    last_sample = Some(Instant::now());
+
+                    let reading = tracker.device()?.map(|device| -> Result<_, Error> {
+                        Ok((device.serial()?, device.get_battery_sample()?))
+                    });
+                    match reading {
+                        Some(Ok((serial, sample))) => {
+                            let path = match output {
+                                Some(ref output) => output.clone(),
+                                None => default_battery_log(&serial, json)?,
+                            };
+                            if log.as_ref().map_or(true, |log| log.path() != path) {
+                                println!("Logging to {}, interrupt to stop", path.display());
+                                log = Some(RotatingFile::new(&path, max_size, keep)?);
+                            }
+                            let line = if json {
+                                sample.to_json()?
+                            } else {
+                                sample.to_string()
+                            };
+                            log.as_mut()
+                                .expect("the log should be open by now")
+                                .write_line(&line)?;
+                        }
+                        Some(Err(error)) => tracker.failed(&error),
+                        None => (),
+                    }
+                }
+
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    #[cfg(feature = "dbus")]
+    if let Some(matches) = matches.subcommand_matches("notify") {
+        let interval = Duration::from_secs(matches.value_of("interval").unwrap_or("60").parse()?);
+        let thresholds = matches
+            .value_of("thresholds")
+            .unwrap_or("20,10,5")
+            .split(',')
+            .map(|threshold| threshold.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        for threshold in &thresholds {
+            ensure!(
+                (0.0..=100.0).contains(threshold),
+                "Thresholds must be from 0 to 100: was {}",
+                threshold
+            );
+        }
+        let mut alerts = BatteryAlerts::new(&thresholds);
+        let mut notifier = Notifier::new()?;
+
+        let running = interrupt_flag()?;
+        let mut tracker = DeviceTracker::new(matches.value_of("device"));
+        let mut last_check: Option<Instant> = None;
+        // Requests to a headset that is off only time out, blocking us from seeing it come back
+        // on, so we stop polling once it turns off until it speaks up again
+        let mut powered_off = false;
+
+        while running.load(Ordering::SeqCst) {
+            let device = match tracker.device()? {
+                Some(device) => device,
+                None => {
+                    powered_off = false;
+                    thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            };
+
+            let events =
+                if !powered_off && last_check.map_or(true, |time| time.elapsed() >= interval) {
+                    last_check = Some(Instant::now());
+                    device
+                        .get_battery_status()
+                        .map(|status| vec![PowerEvent::StatusChanged(status)])
+                } else {
+                    device.wait_for_power_events(Duration::from_secs(1))
+                };
+
+            match events {
+                Ok(events) => {
+                    for event in events {
+                        powered_off = false;
+                        match event {
+                            PowerEvent::StatusChanged(status) => {
+                                if let Some(alert) = alerts.update(&status) {
+                                    let name = device
+                                        .get_device_name()
+                                        .unwrap_or_else(|_| "Headset".to_string());
+                                    if let Err(error) = notifier.alert(&name, &alert) {
+                                        error!("Could not send notification: {}", error);
+                                    }
+                                }
+                            }
+                            PowerEvent::PoweredOff => powered_off = true,
+                            _ => (),
+                        }
+                    }
+                }
+                Err(error) => {
+                    powered_off = false;
+                    tracker.failed(&error);
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "dbus")]
+    if let Some(matches) = matches.subcommand_matches("upower") {
+        let interval = Duration::from_secs(matches.value_of("interval").unwrap_or("60").parse()?);
+        let mut service = BatteryService::new()?;
+
+        let running = interrupt_flag()?;
+        let mut tracker = DeviceTracker::new(matches.value_of("device"));
+        let mut described = false;
+        let mut last_check: Option<Instant> = None;
+        // As with notify, don't poll a headset that is off until it speaks up again
+        let mut powered_off = false;
+
+        // Losing the bus or failing to send a signal shouldn't take the service down, so D-Bus
+        // errors are only logged, and the connection made again if it was lost
+        let publish = |service: &mut BatteryService, status: Option<&battery::BatteryStatus>| {
+            if let Err(error) = service.set_battery(status) {
+                warn!("Could not publish the battery status: {}", error);
+            }
+        };
+
+        while running.load(Ordering::SeqCst) {
+            if let Err(error) = service.process(Duration::from_millis(500)) {
+                warn!("{}", error);
+                thread::sleep(Duration::from_secs(1));
+                match BatteryService::new() {
+                    Ok(new_service) => {
+                        service = new_service;
+                        described = false;
+                        last_check = None;
+                    }
+                    Err(error) => warn!("Could not publish on the session bus again: {}", error),
+                }
+                continue;
+            }
+
+            let device = match tracker.device()? {
+                Some(device) => device,
+                None => {
+                    described = false;
+                    powered_off = false;
+                    publish(&mut service, None);
+                    thread::sleep(Duration::from_millis(500));
+                    continue;
+                }
+            };
+
+            if !described {
+                if let (Ok(name), Ok(serial)) = (device.get_device_name(), device.serial()) {
+                    match service.set_device(&name, &serial) {
+                        Ok(()) => described = true,
+                        Err(error) => warn!("Could not publish the device: {}", error),
+                    }
+                }
+            }
+
+            let events =
+                if !powered_off && last_check.map_or(true, |time| time.elapsed() >= interval) {
+                    last_check = Some(Instant::now());
+                    device
+                        .get_battery_status()
+                        .map(|status| vec![PowerEvent::StatusChanged(status)])
+                } else {
+                    device.wait_for_power_events(Duration::from_millis(500))
+                };
+
+            match events {
+                Ok(events) => {
+                    for event in events {
+                        powered_off = false;
+                        match event {
+                            PowerEvent::StatusChanged(status) => {
+                                publish(&mut service, Some(&status))
+                            }
+                            PowerEvent::PoweredOff => {
+                                powered_off = true;
+                                publish(&mut service, None)
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                Err(error) => {
+                    powered_off = false;
+                    publish(&mut service, None);
+                    tracker.failed(&error);
+                }
+            }
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("sync") {
+        let light = match matches.value_of("light") {
+            Some(light) => Some(light.parse::<Light>()?),
+            None => None,
+        };
+        let mut devices = libg933::find_devices()?;
+        let sysname = match matches.value_of("device") {
+            Some(sysname) => sysname.to_string(),
+            None => devices
+                .keys()
+                .next()
+                .cloned()
+                .ok_or_else(|| format_err!("No devices found"))?,
+        };
+        let mut leader = devices
+            .remove(&sysname)
+            .ok_or_else(|| format_err!("No such device: {}", sysname))?;
+        let mut followers = devices.values_mut().collect::<Vec<_>>();
+
+        let lights = match light {
+            Some(light) => vec![light],
+            None => leader.model().lights.clone(),
+        };
+        for light in lights {
+            leader.sync_lights(light, &mut followers)?;
+        }
+    }
+
     if let Some(matches) = matches.subcommand_matches("raw") {
         let format = matches.value_of("format").unwrap_or("bytes");
         let mut devices = libg933::find_devices()?;
@@ -278,8 +1396,6 @@ fn run() -> Result<(), Error> {
 }
 
 fn main() {
-    use std::io::Write;
-
     env_logger::init().expect("Failed to initialize logger");
 
     ::std::process::exit(match run() {